@@ -11,7 +11,7 @@ CREATE VIEW account_rooted AS
         AND (slot.status = 'Rooted' OR slot.status is NULL)
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 CREATE VIEW account_confirmed AS
     SELECT pubkey, latest_writes.* FROM
         (SELECT
@@ -24,7 +24,7 @@ CREATE VIEW account_confirmed AS
         AND ((slot.status = 'Confirmed' AND NOT slot.uncle) OR slot.status = 'Rooted' OR slot.status is NULL)
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 CREATE VIEW account_processed AS
     SELECT pubkey, latest_writes.* FROM
         (SELECT
@@ -37,7 +37,7 @@ CREATE VIEW account_processed AS
         AND (((slot.status = 'Confirmed' OR slot.status = 'Processed') AND NOT slot.uncle) OR slot.status = 'Rooted' OR slot.status is NULL)
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 
 CREATE VIEW mango_account_rooted AS
     SELECT pubkey, latest_writes.* FROM
@@ -52,7 +52,7 @@ CREATE VIEW mango_account_rooted AS
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN account_write USING(pubkey_id, slot, write_version)
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 CREATE VIEW mango_account_confirmed AS
     SELECT pubkey, latest_writes.* FROM
         (SELECT
@@ -66,7 +66,7 @@ CREATE VIEW mango_account_confirmed AS
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN account_write USING(pubkey_id, slot, write_version)
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 CREATE VIEW mango_account_processed AS
     SELECT pubkey, latest_writes.* FROM
         (SELECT
@@ -80,7 +80,7 @@ CREATE VIEW mango_account_processed AS
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN account_write USING(pubkey_id, slot, write_version)
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 
 CREATE VIEW mango_account_processed_balance AS
     SELECT
@@ -116,7 +116,7 @@ CREATE VIEW mango_group_rooted AS
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN account_write USING(pubkey_id, slot, write_version)
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 CREATE VIEW mango_group_confirmed AS
     SELECT pubkey, latest_writes.* FROM
         (SELECT
@@ -130,7 +130,7 @@ CREATE VIEW mango_group_confirmed AS
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN account_write USING(pubkey_id, slot, write_version)
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 CREATE VIEW mango_group_processed AS
     SELECT pubkey, latest_writes.* FROM
         (SELECT
@@ -144,7 +144,7 @@ CREATE VIEW mango_group_processed AS
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN account_write USING(pubkey_id, slot, write_version)
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 
 CREATE VIEW mango_cache_rooted AS
     SELECT pubkey, latest_writes.* FROM
@@ -159,7 +159,7 @@ CREATE VIEW mango_cache_rooted AS
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN account_write USING(pubkey_id, slot, write_version)
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 CREATE VIEW mango_cache_confirmed AS
     SELECT pubkey, latest_writes.* FROM
         (SELECT
@@ -173,7 +173,7 @@ CREATE VIEW mango_cache_confirmed AS
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN account_write USING(pubkey_id, slot, write_version)
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 CREATE VIEW mango_cache_processed AS
     SELECT pubkey, latest_writes.* FROM
         (SELECT
@@ -187,4 +187,4 @@ CREATE VIEW mango_cache_processed AS
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN account_write USING(pubkey_id, slot, write_version)
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
@@ -55,11 +55,15 @@ CREATE TABLE account_write (
     slot BIGINT NOT NULL,
     write_version BIGINT NOT NULL,
     is_selected BOOL NOT NULL,
+    is_closed BOOL NOT NULL,
     owner_id BIGINT REFERENCES pubkey,
     lamports BIGINT NOT NULL,
     executable BOOL NOT NULL,
     rent_epoch BIGINT NOT NULL,
     data BYTEA,
+    -- (offset, length) pairs of the parts of the account data that `data` holds,
+    -- NULL if it holds all of it
+    data_slices BIGINT[],
     PRIMARY KEY (pubkey_id, slot, write_version)
 );
 CREATE INDEX account_write_searchkey on account_write(pubkey_id, slot DESC, write_version DESC);
@@ -72,4 +76,13 @@ CREATE TABLE slot (
     status "SlotStatus" NOT NULL,
     uncle BOOL NOT NULL
 );
-CREATE INDEX ON slot (parent);
\ No newline at end of file
+CREATE INDEX ON slot (parent);
+
+-- Migrations for databases created with an older version of this script,
+-- run them manually when upgrading:
+--
+-- account_write.is_closed was added:
+-- ALTER TABLE account_write ADD COLUMN is_closed BOOL NOT NULL DEFAULT false;
+--
+-- account_write.data_slices was added:
+-- ALTER TABLE account_write ADD COLUMN data_slices BIGINT[];
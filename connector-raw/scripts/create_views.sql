@@ -11,7 +11,7 @@ CREATE VIEW account_rooted AS
         AND (slot.status = 'Rooted' OR slot.status is NULL)
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 CREATE VIEW account_confirmed AS
     SELECT pubkey, latest_writes.* FROM
         (SELECT
@@ -24,7 +24,7 @@ CREATE VIEW account_confirmed AS
         AND ((slot.status = 'Confirmed' AND NOT slot.uncle) OR slot.status = 'Rooted' OR slot.status is NULL)
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
 CREATE VIEW account_processed AS
     SELECT pubkey, latest_writes.* FROM
         (SELECT
@@ -37,4 +37,4 @@ CREATE VIEW account_processed AS
         AND (((slot.status = 'Confirmed' OR slot.status = 'Processed') AND NOT slot.uncle) OR slot.status = 'Rooted' OR slot.status is NULL)
         ORDER BY pubkey_id, slot DESC, write_version DESC) latest_writes
     LEFT JOIN pubkey USING(pubkey_id)
-    WHERE is_selected;
+    WHERE is_selected AND NOT is_closed;
@@ -30,23 +30,13 @@ async fn main() -> anyhow::Result<()> {
         postgres_target::init(&config.postgres_target, account_tables, metrics_tx.clone()).await?;
 
     info!("postgres done");
-    let use_geyser = true;
-    if use_geyser {
-        grpc_plugin_source::process_events(
-            &config.source,
-            account_write_queue_sender,
-            slot_queue_sender,
-            metrics_tx,
-        )
-        .await;
-    } else {
-        websocket_source::process_events(
-            &config.source,
-            account_write_queue_sender,
-            slot_queue_sender,
-        )
-        .await;
-    }
+    source::process_events(
+        &config.source,
+        account_write_queue_sender,
+        slot_queue_sender,
+        metrics_tx,
+    )
+    .await?;
 
     Ok(())
 }
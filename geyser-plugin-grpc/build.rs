@@ -17,8 +17,9 @@ fn main() -> anyhow::Result<()> {
         git_version::git_version!()
     );
 
-    // Extract Solana version
-    let lockfile = Lockfile::load("./Cargo.lock")?;
+    // Extract Solana version from the workspace lockfile
+    println!("cargo:rerun-if-changed=../Cargo.lock");
+    let lockfile = Lockfile::load("../Cargo.lock")?;
     println!(
         "cargo:rustc-env=SOLANA_SDK_VERSION={}",
         lockfile
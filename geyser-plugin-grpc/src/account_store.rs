@@ -0,0 +1,201 @@
+use {
+    crate::geyser_plugin_grpc::geyser_proto::AccountWrite,
+    std::collections::{hash_map::Entry, BTreeMap, HashMap},
+};
+
+/// Latest rooted write of each selected account, for serving snapshots
+///
+/// Writes are kept per slot until the slot is rooted, so writes on forks that
+/// get abandoned never show up in snapshots.
+#[derive(Debug, Default)]
+pub struct AccountStore {
+    accounts: HashMap<[u8; 32], AccountWrite>,
+
+    /// Latest write per account of slots that aren't rooted yet
+    pending: BTreeMap<u64, HashMap<[u8; 32], AccountWrite>>,
+
+    /// Parents of slots that aren't rooted yet
+    parents: BTreeMap<u64, u64>,
+
+    /// Highest rooted slot, or the slot of the startup writes before the first root
+    slot: u64,
+}
+
+impl AccountStore {
+    pub fn update(&mut self, pubkey: [u8; 32], write: &AccountWrite) {
+        // Startup writes come from a snapshot of rooted state
+        if write.is_startup {
+            self.slot = self.slot.max(write.slot);
+            self.apply(pubkey, write.clone());
+            return;
+        }
+        if write.slot <= self.slot {
+            return;
+        }
+        match self.pending.entry(write.slot).or_default().entry(pubkey) {
+            Entry::Occupied(mut entry) => {
+                if write.write_version >= entry.get().write_version {
+                    entry.insert(write.clone());
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(write.clone());
+            }
+        }
+    }
+
+    /// Records the parent of a slot, to find the slots a root confirms
+    pub fn on_slot(&mut self, slot: u64, parent: Option<u64>) {
+        if let (Some(parent), true) = (parent, slot > self.slot) {
+            self.parents.insert(slot, parent);
+        }
+    }
+
+    /// Applies the writes of the rooted slot and its ancestors since the
+    /// previous root, and drops the writes of all other slots up to it
+    pub fn on_rooted(&mut self, slot: u64) {
+        if slot <= self.slot {
+            return;
+        }
+
+        let mut rooted_slots = vec![slot];
+        let mut current = slot;
+        while let Some(&parent) = self.parents.get(&current) {
+            if parent <= self.slot {
+                break;
+            }
+            rooted_slots.push(parent);
+            current = parent;
+        }
+        for rooted_slot in rooted_slots.into_iter().rev() {
+            if let Some(writes) = self.pending.remove(&rooted_slot) {
+                for (pubkey, write) in writes {
+                    self.apply(pubkey, write);
+                }
+            }
+        }
+
+        // Slots that weren't rooted are on abandoned forks
+        self.pending = self.pending.split_off(&(slot + 1));
+        self.parents = self.parents.split_off(&(slot + 1));
+        self.slot = slot;
+    }
+
+    fn apply(&mut self, pubkey: [u8; 32], write: AccountWrite) {
+        let is_newer = |previous: &AccountWrite| {
+            (write.slot, write.write_version) >= (previous.slot, previous.write_version)
+        };
+        match self.accounts.entry(pubkey) {
+            Entry::Occupied(mut entry) => {
+                if !is_newer(entry.get()) {
+                    return;
+                }
+                // Closed or deselected accounts don't show up in snapshots
+                if write.lamports == 0 || write.is_closed || !write.is_selected {
+                    entry.remove();
+                } else {
+                    entry.insert(write);
+                }
+            }
+            Entry::Vacant(entry) => {
+                if write.lamports != 0 && !write.is_closed && write.is_selected {
+                    entry.insert(write);
+                }
+            }
+        }
+    }
+
+    /// Clones the latest rooted writes of all accounts matching the filter
+    ///
+    /// Returns the highest rooted slot along with them.
+    pub fn snapshot(&self, filter: impl Fn(&AccountWrite) -> bool) -> (u64, Vec<AccountWrite>) {
+        let accounts = self
+            .accounts
+            .values()
+            .filter(|write| filter(write))
+            .cloned()
+            .collect();
+        (self.slot, accounts)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn write(slot: u64, write_version: u64, lamports: u64) -> AccountWrite {
+        AccountWrite {
+            slot,
+            write_version,
+            lamports,
+            is_selected: true,
+            ..Default::default()
+        }
+    }
+
+    fn lamports(store: &AccountStore) -> Vec<u64> {
+        let mut lamports = store
+            .snapshot(|_| true)
+            .1
+            .iter()
+            .map(|w| w.lamports)
+            .collect::<Vec<_>>();
+        lamports.sort_unstable();
+        lamports
+    }
+
+    #[test]
+    fn test_account_store_latest_write() {
+        let mut store = AccountStore::default();
+        store.update([1; 32], &write(10, 5, 100));
+        store.update([1; 32], &write(10, 4, 200));
+        store.update([2; 32], &write(11, 1, 300));
+
+        // nothing is visible before it's rooted
+        assert_eq!(store.snapshot(|_| true), (0, vec![]));
+
+        store.on_slot(11, Some(10));
+        store.on_rooted(11);
+        let (slot, _) = store.snapshot(|_| true);
+        assert_eq!(slot, 11);
+        assert_eq!(lamports(&store), vec![100, 300]);
+
+        // closed accounts are removed
+        store.update([2; 32], &write(12, 2, 0));
+        store.on_rooted(12);
+        let (slot, accounts) = store.snapshot(|_| true);
+        assert_eq!(slot, 12);
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(store.snapshot(|w| w.lamports > 100).1.len(), 0);
+
+        // writes for slots that are already rooted are ignored
+        store.update([1; 32], &write(12, 9, 400));
+        store.on_rooted(13);
+        assert_eq!(lamports(&store), vec![100]);
+    }
+
+    #[test]
+    fn test_account_store_forks() {
+        let mut store = AccountStore::default();
+        let mut startup = write(5, 1, 100);
+        startup.is_startup = true;
+        store.update([1; 32], &startup);
+        assert_eq!(store.snapshot(|_| true).0, 5);
+
+        // slot 7 is on a fork that gets abandoned, 6 and 8 get rooted
+        store.on_slot(6, Some(5));
+        store.update([1; 32], &write(6, 1, 200));
+        store.on_slot(7, Some(6));
+        store.update([1; 32], &write(7, 1, 300));
+        store.update([2; 32], &write(7, 1, 300));
+        store.on_slot(8, Some(6));
+        store.update([2; 32], &write(8, 1, 400));
+
+        store.on_rooted(8);
+        let (slot, _) = store.snapshot(|_| true);
+        assert_eq!(slot, 8);
+        assert_eq!(lamports(&store), vec![200, 400]);
+        assert!(store.pending.is_empty());
+        assert!(store.parents.is_empty());
+    }
+}
@@ -1,10 +1,210 @@
 use {log::*, std::collections::HashSet};
 
+const SPL_TOKEN_ACCOUNT_LEN: usize = 165;
+const SPL_TOKEN_MULTISIG_LEN: usize = 355;
+const SPL_TOKEN_ACCOUNT_STATE_OFFSET: usize = 108;
+const SPL_TOKEN_ACCOUNT_TYPE_ACCOUNT: u8 = 2;
+
+/// Filter on account data, with the same semantics as the getProgramAccounts filters
+#[derive(Clone, Debug, PartialEq)]
+pub enum AccountFilter {
+    /// Data must contain `bytes` at `offset`
+    Memcmp { offset: usize, bytes: Vec<u8> },
+    /// Data must have exactly this length
+    DataSize(u64),
+    /// Data must be an initialized SPL token account
+    TokenAccountState,
+}
+
+impl AccountFilter {
+    /// Parses the JSON representation used by the getProgramAccounts RPC call, like
+    /// `{"memcmp": {"offset": 0, "bytes": "<base58>"}}`, `{"dataSize": 165}` or
+    /// `"tokenAccountState"`.
+    ///
+    /// Memcmp bytes may also be given as an array of numbers.
+    pub fn from_config(filter: &serde_json::Value) -> anyhow::Result<Self> {
+        if filter.as_str() == Some("tokenAccountState") {
+            return Ok(AccountFilter::TokenAccountState);
+        }
+        if let Some(data_size) = filter.get("dataSize") {
+            let data_size = data_size
+                .as_u64()
+                .ok_or_else(|| anyhow::anyhow!("Expected `dataSize` as integer"))?;
+            return Ok(AccountFilter::DataSize(data_size));
+        }
+        if let Some(memcmp) = filter.get("memcmp") {
+            let offset = memcmp["offset"]
+                .as_u64()
+                .ok_or_else(|| anyhow::anyhow!("Expected memcmp `offset` as integer"))?
+                as usize;
+            let bytes = match &memcmp["bytes"] {
+                serde_json::Value::String(bytes) => {
+                    match memcmp["encoding"].as_str().unwrap_or("base58") {
+                        "base58" => bs58::decode(bytes).into_vec()?,
+                        encoding => anyhow::bail!("Unsupported memcmp encoding {}", encoding),
+                    }
+                }
+                serde_json::Value::Array(bytes) => bytes
+                    .iter()
+                    .map(|byte| {
+                        byte.as_u64()
+                            .filter(|byte| *byte <= u8::MAX as u64)
+                            .map(|byte| byte as u8)
+                            .ok_or_else(|| anyhow::anyhow!("Expected memcmp `bytes` as u8 array"))
+                    })
+                    .collect::<Result<_, _>>()?,
+                _ => anyhow::bail!("Expected memcmp `bytes` as base58 String or u8 array"),
+            };
+            return Ok(AccountFilter::Memcmp { offset, bytes });
+        }
+        anyhow::bail!("Unknown account filter {}", filter)
+    }
+
+    /// The JSON representation accepted by `from_config`
+    pub fn to_config(&self) -> serde_json::Value {
+        match self {
+            AccountFilter::Memcmp { offset, bytes } => serde_json::json!({
+                "memcmp": {"offset": offset, "bytes": bs58::encode(bytes).into_string()}
+            }),
+            AccountFilter::DataSize(data_size) => serde_json::json!({ "dataSize": data_size }),
+            AccountFilter::TokenAccountState => serde_json::json!("tokenAccountState"),
+        }
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            AccountFilter::Memcmp { offset, bytes } => data
+                .get(*offset..offset.saturating_add(bytes.len()))
+                .map_or(false, |slice| slice == bytes.as_slice()),
+            AccountFilter::DataSize(data_size) => data.len() as u64 == *data_size,
+            AccountFilter::TokenAccountState => {
+                // Same as spl_token_2022::state::Account::valid_account_data(), which
+                // accepts plain token accounts and ones with extensions
+                let is_account = data.len() == SPL_TOKEN_ACCOUNT_LEN
+                    || (data.len() > SPL_TOKEN_ACCOUNT_LEN
+                        && data.len() != SPL_TOKEN_MULTISIG_LEN
+                        && data[SPL_TOKEN_ACCOUNT_LEN] == SPL_TOKEN_ACCOUNT_TYPE_ACCOUNT);
+                is_account && data[SPL_TOKEN_ACCOUNT_STATE_OFFSET] != 0
+            }
+        }
+    }
+
+    /// Like `matches`, for data that only holds the `available` slices of the account data
+    ///
+    /// Only memcmp filters on available bytes can be checked, other filters never match.
+    pub fn matches_sliced(&self, available: &[DataSlice], data: &[u8]) -> bool {
+        match self {
+            AccountFilter::Memcmp { offset, bytes } => {
+                let compared = DataSlice {
+                    offset: *offset,
+                    length: bytes.len(),
+                };
+                let (sliced, copied) = reslice_data(available, data, &[compared]);
+                copied.iter().map(|slice| slice.length).sum::<usize>() == bytes.len()
+                    && sliced == *bytes
+            }
+            AccountFilter::DataSize(_) | AccountFilter::TokenAccountState => false,
+        }
+    }
+}
+
+/// Range of the account data to forward instead of all of it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DataSlice {
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl DataSlice {
+    /// Parses the JSON representation `{"offset": 0, "length": 200}`
+    pub fn from_config(slice: &serde_json::Value) -> anyhow::Result<Self> {
+        let field = |name: &str| {
+            slice[name]
+                .as_u64()
+                .map(|value| value as usize)
+                .ok_or_else(|| anyhow::anyhow!("Expected data slice `{}` as integer", name))
+        };
+        Ok(DataSlice {
+            offset: field("offset")?,
+            length: field("length")?,
+        })
+    }
+
+    /// The JSON representation accepted by `from_config`
+    pub fn to_config(&self) -> serde_json::Value {
+        serde_json::json!({"offset": self.offset, "length": self.length})
+    }
+}
+
+/// Concatenates the given slices of the data
+///
+/// Slices are truncated to the data, the slices that were actually copied are
+/// returned along with the bytes.
+pub fn slice_data(slices: &[DataSlice], data: &[u8]) -> (Vec<u8>, Vec<DataSlice>) {
+    let mut sliced = Vec::with_capacity(slices.iter().map(|slice| slice.length).sum());
+    let copied = slices
+        .iter()
+        .map(|slice| {
+            let offset = slice.offset.min(data.len());
+            let length = slice.length.min(data.len() - offset);
+            sliced.extend_from_slice(&data[offset..offset + length]);
+            DataSlice { offset, length }
+        })
+        .collect();
+    (sliced, copied)
+}
+
+/// Like `slice_data`, for data that only holds the `available` slices of the account data
+///
+/// Parts of the requested slices outside of the available ones are left out,
+/// the returned slices are the parts that were actually copied.
+pub fn reslice_data(
+    available: &[DataSlice],
+    data: &[u8],
+    slices: &[DataSlice],
+) -> (Vec<u8>, Vec<DataSlice>) {
+    let mut sliced = vec![];
+    let mut copied = vec![];
+    for slice in slices {
+        let slice_end = slice.offset.saturating_add(slice.length);
+        let mut position = 0;
+        for part in available {
+            let offset = slice.offset.max(part.offset);
+            let end = slice_end.min(part.offset + part.length);
+            if offset < end {
+                let start = position + offset - part.offset;
+                if let Some(bytes) = data.get(start..start + end - offset) {
+                    sliced.extend_from_slice(bytes);
+                    copied.push(DataSlice {
+                        offset,
+                        length: end - offset,
+                    });
+                }
+            }
+            position += part.length;
+        }
+    }
+    (sliced, copied)
+}
+
 #[derive(Debug)]
 pub struct AccountsSelector {
     pub accounts: HashSet<Vec<u8>>,
     pub owners: HashSet<Vec<u8>>,
+    /// Accounts selected through `owners` must match all of these
+    pub filters: Vec<AccountFilter>,
+    /// Only forward these parts of the account data, all of it if empty
+    ///
+    /// Applied when the plugin receives account writes, so snapshots and
+    /// subscribers only ever see these parts. Subscribers can request narrower
+    /// slices, and data filters of restrictions can only check memcmp filters
+    /// on the kept bytes.
+    pub data_slices: Vec<DataSlice>,
     pub select_all_accounts: bool,
+    /// Forward transactions that mention selected accounts
+    pub select_transactions: bool,
+    /// Also forward vote transactions, if transactions are selected
+    pub select_vote_transactions: bool,
 }
 
 impl Default for AccountsSelector {
@@ -12,7 +212,11 @@ impl Default for AccountsSelector {
         Self {
             accounts: HashSet::default(),
             owners: HashSet::default(),
+            filters: Vec::default(),
+            data_slices: Vec::default(),
             select_all_accounts: true,
+            select_transactions: false,
+            select_vote_transactions: false,
         }
     }
 }
@@ -38,12 +242,88 @@ impl AccountsSelector {
                 .iter()
                 .map(|key| bs58::decode(key).into_vec())
                 .collect::<Result<_, _>>()?,
+            filters: Vec::new(),
+            data_slices: Vec::new(),
             select_all_accounts: false,
+            select_transactions: false,
+            select_vote_transactions: false,
+        })
+    }
+
+    /// The JSON representation in the format of the plugin's `accounts_selector` config
+    pub fn to_config(&self) -> serde_json::Value {
+        let encode = |keys: &HashSet<Vec<u8>>| {
+            keys.iter()
+                .map(|key| bs58::encode(key).into_string())
+                .collect::<Vec<_>>()
+        };
+        let accounts = if self.select_all_accounts {
+            vec!["*".to_string()]
+        } else {
+            encode(&self.accounts)
+        };
+        serde_json::json!({
+            "accounts": accounts,
+            "owners": encode(&self.owners),
+            "filters": self.filters.iter().map(AccountFilter::to_config).collect::<Vec<_>>(),
+            "data_slices": self.data_slices.iter().map(DataSlice::to_config).collect::<Vec<_>>(),
+            "transactions": self.select_transactions,
+            "vote_transactions": self.select_vote_transactions,
         })
     }
 
-    pub fn is_account_selected(&self, account: &[u8], owner: &[u8]) -> bool {
-        self.select_all_accounts || self.accounts.contains(account) || self.owners.contains(owner)
+    pub fn is_account_selected(&self, account: &[u8], owner: &[u8], data: &[u8]) -> bool {
+        self.select_all_accounts
+            || self.accounts.contains(account)
+            || (self.owners.contains(owner) && self.filters.iter().all(|f| f.matches(data)))
+    }
+
+    /// Like `is_account_selected`, for data that only holds the `available`
+    /// slices of the account data
+    pub fn is_sliced_account_selected(
+        &self,
+        account: &[u8],
+        owner: &[u8],
+        available: &[DataSlice],
+        data: &[u8],
+    ) -> bool {
+        self.select_all_accounts
+            || self.accounts.contains(account)
+            || (self.owners.contains(owner)
+                && self
+                    .filters
+                    .iter()
+                    .all(|f| f.matches_sliced(available, data)))
+    }
+
+    /// Turns the selector into a restriction applied on top of the global selector
+    ///
+    /// Which transactions are broadcast at all is decided by the global
+    /// selector, restrictions pass transactions of either kind that mention
+    /// their accounts.
+    pub fn into_restriction(mut self) -> Self {
+        self.select_transactions = true;
+        self.select_vote_transactions = true;
+        self
+    }
+
+    /// Transactions are selected if they mention a selected account
+    ///
+    /// Since owners aren't known for transaction account keys, `is_tracked` is
+    /// used to check for accounts that were selected through their owner.
+    pub fn is_transaction_selected(
+        &self,
+        is_vote: bool,
+        account_keys: &[&[u8]],
+        is_tracked: impl Fn(&[u8]) -> bool,
+    ) -> bool {
+        if !self.select_transactions || (is_vote && !self.select_vote_transactions) {
+            return false;
+        }
+        self.select_all_accounts
+            || account_keys
+                .iter()
+                .any(|key| self.accounts.contains(*key) || is_tracked(*key))
     }
 }
 
@@ -57,4 +337,117 @@ pub(crate) mod tests {
 
         AccountsSelector::new(&[], &["9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin"]).unwrap();
     }
+
+    #[test]
+    fn test_account_filters() {
+        let owner = bs58::decode("9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin")
+            .into_vec()
+            .unwrap();
+        let mut selector =
+            AccountsSelector::new(&[], &["9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin"]).unwrap();
+        selector.filters = vec![
+            AccountFilter::from_config(&serde_json::json!({"dataSize": 4})).unwrap(),
+            AccountFilter::from_config(
+                &serde_json::json!({"memcmp": {"offset": 1, "bytes": [2, 3]}}),
+            )
+            .unwrap(),
+        ];
+        assert!(selector.is_account_selected(&[0; 32], &owner, &[1, 2, 3, 4]));
+        assert!(!selector.is_account_selected(&[0; 32], &owner, &[1, 2, 3]));
+        assert!(!selector.is_account_selected(&[0; 32], &owner, &[1, 2, 4, 4]));
+        assert!(!selector.is_account_selected(&[0; 32], &[0; 32], &[1, 2, 3, 4]));
+
+        let memcmp = serde_json::json!({"memcmp": {"offset": 2, "bytes": bs58::encode([7u8]).into_string()}});
+        assert_eq!(
+            AccountFilter::from_config(&memcmp).unwrap(),
+            AccountFilter::Memcmp {
+                offset: 2,
+                bytes: vec![7]
+            }
+        );
+
+        let token_account_state =
+            AccountFilter::from_config(&serde_json::json!("tokenAccountState")).unwrap();
+        let mut token_account = vec![0; SPL_TOKEN_ACCOUNT_LEN];
+        assert!(!token_account_state.matches(&token_account));
+        token_account[SPL_TOKEN_ACCOUNT_STATE_OFFSET] = 1;
+        assert!(token_account_state.matches(&token_account));
+    }
+
+    #[test]
+    fn test_data_slices() {
+        let slices = [
+            DataSlice::from_config(&serde_json::json!({"offset": 1, "length": 2})).unwrap(),
+            DataSlice {
+                offset: 4,
+                length: 10,
+            },
+            DataSlice {
+                offset: 20,
+                length: 1,
+            },
+        ];
+        let (data, copied) = slice_data(&slices, &[0, 1, 2, 3, 4, 5]);
+        assert_eq!(data, vec![1, 2, 4, 5]);
+        assert_eq!(
+            copied,
+            vec![
+                DataSlice {
+                    offset: 1,
+                    length: 2
+                },
+                DataSlice {
+                    offset: 4,
+                    length: 2
+                },
+                DataSlice {
+                    offset: 6,
+                    length: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reslice_data() {
+        let slice = |offset, length| DataSlice { offset, length };
+        // The bytes at offsets 2..5 and 8..10 of the account data
+        let available = [slice(2, 3), slice(8, 2)];
+        let data = [2, 3, 4, 8, 9];
+
+        let (sliced, copied) = reslice_data(&available, &data, &[slice(3, 6), slice(0, 1)]);
+        assert_eq!(sliced, vec![3, 4, 8]);
+        assert_eq!(copied, vec![slice(3, 2), slice(8, 1)]);
+
+        let memcmp = |offset, bytes: &[u8]| AccountFilter::Memcmp {
+            offset,
+            bytes: bytes.to_vec(),
+        };
+        assert!(memcmp(3, &[3, 4]).matches_sliced(&available, &data));
+        assert!(!memcmp(3, &[3, 5]).matches_sliced(&available, &data));
+        assert!(!memcmp(4, &[4, 5]).matches_sliced(&available, &data));
+        assert!(!AccountFilter::DataSize(10).matches_sliced(&available, &data));
+    }
+
+    #[test]
+    fn test_transaction_selection() {
+        let account = bs58::decode("9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin")
+            .into_vec()
+            .unwrap();
+        let account = account.as_slice();
+        let other = [1u8; 32];
+        let other = &other[..];
+        let mut selector =
+            AccountsSelector::new(&["9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin"], &[]).unwrap();
+        assert!(!selector.is_transaction_selected(false, &[account], |_| false));
+
+        selector.select_transactions = true;
+        assert!(selector.is_transaction_selected(false, &[other, account], |_| false));
+        assert!(!selector.is_transaction_selected(false, &[other], |_| false));
+        assert!(selector.is_transaction_selected(false, &[other], |key| key == other));
+        assert!(!selector.is_transaction_selected(true, &[account], |_| false));
+
+        selector.select_vote_transactions = true;
+        assert!(selector.is_transaction_selected(true, &[account], |_| false));
+    }
 }
@@ -0,0 +1,151 @@
+use {
+    crate::prom::{ACTIVE_ACCOUNTS, ACTIVE_ACCOUNTS_EVICTIONS_TOTAL},
+    serde_derive::Deserialize,
+    std::{
+        collections::{BTreeMap, HashMap},
+        convert::TryInto,
+    },
+};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActiveAccountsConfig {
+    /// Maximum number of tracked accounts, the least recently written ones are
+    /// evicted beyond it (0 for no limit)
+    #[serde(default)]
+    pub max_size: usize,
+    /// Stop tracking accounts once the write that closed them was broadcast
+    #[serde(default)]
+    pub evict_closed: bool,
+}
+
+/// Accounts that were selected before
+///
+/// Writes to them are broadcast even if they don't match the accounts selector
+/// anymore, to catch closures and account reuse. Writes to evicted accounts
+/// are only broadcast if they match the selector again.
+#[derive(Debug, Default)]
+pub struct ActiveAccounts {
+    config: ActiveAccountsConfig,
+
+    /// Whether this is the plugin-wide set that the metrics describe
+    report_metrics: bool,
+
+    /// pubkey -> sequence number of the latest write
+    accounts: HashMap<[u8; 32], u64>,
+
+    /// sequence number -> pubkey, least recently written first
+    by_last_write: BTreeMap<u64, [u8; 32]>,
+
+    next_sequence: u64,
+}
+
+impl ActiveAccounts {
+    pub fn new(config: ActiveAccountsConfig) -> Self {
+        Self {
+            config,
+            report_metrics: true,
+            ..Default::default()
+        }
+    }
+
+    /// Same as `new`, but doesn't report metrics, for the accounts a single
+    /// subscriber saw
+    pub fn for_subscriber(config: ActiveAccountsConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn contains(&self, pubkey: &[u8]) -> bool {
+        pubkey.try_into().map_or(false, |pubkey: [u8; 32]| {
+            self.accounts.contains_key(&pubkey)
+        })
+    }
+
+    /// Tracks an account write that was broadcast
+    pub fn on_write(&mut self, pubkey: [u8; 32], is_closed: bool) {
+        if is_closed && self.config.evict_closed {
+            if self.remove(&pubkey) {
+                self.count_eviction("closed");
+            }
+            self.report_len();
+            return;
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        if let Some(previous) = self.accounts.insert(pubkey, sequence) {
+            self.by_last_write.remove(&previous);
+        }
+        self.by_last_write.insert(sequence, pubkey);
+
+        if self.config.max_size > 0 {
+            while self.accounts.len() > self.config.max_size {
+                let oldest = *self.by_last_write.keys().next().expect("not empty");
+                let evicted = self.by_last_write.remove(&oldest).expect("exists");
+                self.accounts.remove(&evicted);
+                self.count_eviction("max_size");
+            }
+        }
+        self.report_len();
+    }
+
+    fn count_eviction(&self, reason: &str) {
+        if self.report_metrics {
+            ACTIVE_ACCOUNTS_EVICTIONS_TOTAL
+                .with_label_values(&[reason])
+                .inc();
+        }
+    }
+
+    fn report_len(&self) {
+        if self.report_metrics {
+            ACTIVE_ACCOUNTS.set(self.accounts.len() as i64);
+        }
+    }
+
+    fn remove(&mut self, pubkey: &[u8; 32]) -> bool {
+        match self.accounts.remove(pubkey) {
+            Some(sequence) => {
+                self.by_last_write.remove(&sequence);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_accounts_eviction() {
+        let mut active_accounts = ActiveAccounts::new(ActiveAccountsConfig {
+            max_size: 2,
+            evict_closed: true,
+        });
+        active_accounts.on_write([1; 32], false);
+        active_accounts.on_write([2; 32], false);
+        active_accounts.on_write([1; 32], false);
+        active_accounts.on_write([3; 32], false);
+        assert_eq!(active_accounts.len(), 2);
+        assert!(active_accounts.contains(&[1; 32]));
+        assert!(!active_accounts.contains(&[2; 32]));
+        assert!(active_accounts.contains(&[3; 32]));
+
+        active_accounts.on_write([3; 32], true);
+        assert_eq!(active_accounts.len(), 1);
+        assert!(!active_accounts.contains(&[3; 32]));
+    }
+}
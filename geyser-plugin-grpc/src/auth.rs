@@ -0,0 +1,126 @@
+use {
+    crate::{accounts_selector::AccountsSelector, geyser_plugin_grpc::Plugin},
+    serde_derive::Deserialize,
+    std::{collections::HashMap, sync::Arc},
+    tonic::{Request, Status},
+};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessTokenConfig {
+    pub token: String,
+    /// Restricts the token to accounts matching this selector, in the same
+    /// format as the plugin's `accounts_selector`
+    #[serde(default)]
+    pub accounts_selector: Option<serde_json::Value>,
+}
+
+/// Accounts a request is allowed to access, attached to authenticated requests
+///
+/// If the selector is None, access is unrestricted.
+#[derive(Clone, Debug)]
+pub struct AccessScope(pub Option<Arc<AccountsSelector>>);
+
+/// Checks the access token that clients send as `authorization: Bearer <token>`
+#[derive(Clone, Debug, Default)]
+pub struct AccessTokens {
+    scopes: Arc<HashMap<String, AccessScope>>,
+}
+
+impl AccessTokens {
+    pub fn new(configs: &[AccessTokenConfig]) -> anyhow::Result<Self> {
+        let scopes = configs
+            .iter()
+            .map(|config| {
+                let accounts_selector = config
+                    .accounts_selector
+                    .as_ref()
+                    .map(|selector| {
+                        let selector = Plugin::create_accounts_selector_from_config(selector)?;
+                        Ok::<_, anyhow::Error>(Arc::new(selector.into_restriction()))
+                    })
+                    .transpose()?;
+                Ok((config.token.clone(), AccessScope(accounts_selector)))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            scopes: Arc::new(scopes),
+        })
+    }
+
+    /// Interceptor for the gRPC service, accepts everything if no tokens are configured
+    pub fn check(&self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if self.scopes.is_empty() {
+            request.extensions_mut().insert(AccessScope(None));
+            return Ok(request);
+        }
+
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let scope = match token.and_then(|token| self.scopes.get(token)) {
+            Some(scope) => scope.clone(),
+            None => return Err(Status::unauthenticated("invalid access token")),
+        };
+        request.extensions_mut().insert(scope);
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {super::*, tonic::Code};
+
+    fn request_with_token(token: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(token) = token {
+            request.metadata_mut().insert(
+                "authorization",
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+        }
+        request
+    }
+
+    fn scope(request: &Request<()>) -> &AccessScope {
+        request.extensions().get::<AccessScope>().unwrap()
+    }
+
+    #[test]
+    fn test_access_tokens() {
+        let tokens = AccessTokens::new(&[
+            AccessTokenConfig {
+                token: "full".to_string(),
+                accounts_selector: None,
+            },
+            AccessTokenConfig {
+                token: "scoped".to_string(),
+                accounts_selector: Some(serde_json::json!({
+                    "owners": ["9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin"],
+                })),
+            },
+        ])
+        .unwrap();
+
+        let err = tokens.check(request_with_token(None)).unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+        let err = tokens.check(request_with_token(Some("wrong"))).unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+
+        let request = tokens.check(request_with_token(Some("full"))).unwrap();
+        assert!(scope(&request).0.is_none());
+
+        let request = tokens.check(request_with_token(Some("scoped"))).unwrap();
+        let selector = scope(&request).0.as_ref().unwrap();
+        assert_eq!(selector.owners.len(), 1);
+        assert!(selector.select_transactions);
+
+        // Without configured tokens, everything is accepted
+        let request = AccessTokens::default()
+            .check(request_with_token(None))
+            .unwrap();
+        assert!(scope(&request).0.is_none());
+    }
+}
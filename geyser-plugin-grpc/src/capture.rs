@@ -0,0 +1,284 @@
+use {
+    crate::{
+        geyser_plugin_grpc::geyser_proto::{update::UpdateOneof, Gap, Update},
+        prom::{CAPTURE_BYTES_TOTAL, CAPTURE_LAG_EVENTS_TOTAL, CAPTURE_UPDATES_TOTAL},
+        replay_buffer::update_slot,
+    },
+    log::*,
+    prost::Message,
+    serde_derive::Deserialize,
+    std::{
+        fs::File,
+        io::{BufWriter, Write},
+        path::{Path, PathBuf},
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+    tokio::sync::broadcast,
+};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Directory the capture files are written to
+    pub directory: String,
+    /// Start a new file once this many uncompressed bytes were written to the
+    /// current one (0 for no limit)
+    #[serde(default = "default_max_file_bytes")]
+    pub max_file_bytes: u64,
+    /// Start a new file once the current one is this old (0 for no limit)
+    #[serde(default = "default_max_file_age_secs")]
+    pub max_file_age_secs: u64,
+    /// zstd compression level, 0 for the zstd default
+    #[serde(default)]
+    pub compression_level: i32,
+}
+
+fn default_max_file_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_max_file_age_secs() -> u64 {
+    3600
+}
+
+/// Suffix of capture files that are still being written
+const PARTIAL_SUFFIX: &str = ".partial";
+
+struct CaptureFile {
+    encoder: zstd::stream::write::Encoder<'static, BufWriter<File>>,
+    path: PathBuf,
+    bytes: u64,
+    opened: Instant,
+}
+
+/// Writes updates as length-delimited protobuf to rotating zstd compressed files
+///
+/// Files are named `updates-<unix millis>-<sequence>.pb.zst` and carry a
+/// `.partial` suffix until they are complete.
+pub struct CaptureWriter {
+    config: CaptureConfig,
+    file: Option<CaptureFile>,
+
+    /// Number of files started so far
+    sequence: u64,
+}
+
+impl CaptureWriter {
+    pub fn new(config: CaptureConfig) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&config.directory)?;
+        Ok(Self {
+            config,
+            file: None,
+            sequence: 0,
+        })
+    }
+
+    pub fn write(&mut self, update: &Update) -> std::io::Result<()> {
+        if self.is_rotation_due() {
+            self.finish()?;
+        }
+        if self.file.is_none() {
+            self.file = Some(self.open()?);
+        }
+        let file = self.file.as_mut().expect("file was opened");
+
+        let encoded = update.encode_length_delimited_to_vec();
+        file.encoder.write_all(&encoded)?;
+        file.bytes += encoded.len() as u64;
+        CAPTURE_UPDATES_TOTAL.inc();
+        CAPTURE_BYTES_TOTAL.inc_by(encoded.len() as u64);
+        Ok(())
+    }
+
+    /// Writes out buffered data, so it survives a crash
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.encoder.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Completes the current file, the next write starts a new one
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        file.encoder.finish()?.flush()?;
+        let partial_path = partial_path(&file.path);
+        std::fs::rename(&partial_path, &file.path)?;
+        info!("finished capture file {:?}", file.path);
+        Ok(())
+    }
+
+    fn is_rotation_due(&self) -> bool {
+        self.file.as_ref().map_or(false, |file| {
+            (self.config.max_file_bytes > 0 && file.bytes >= self.config.max_file_bytes)
+                || (self.config.max_file_age_secs > 0
+                    && file.opened.elapsed() >= Duration::from_secs(self.config.max_file_age_secs))
+        })
+    }
+
+    fn open(&mut self) -> std::io::Result<CaptureFile> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // The sequence number keeps files that are rotated quickly in order
+        let path = Path::new(&self.config.directory)
+            .join(format!("updates-{}-{:06}.pb.zst", millis, self.sequence));
+
+        let writer = BufWriter::new(File::create(partial_path(&path))?);
+        let encoder = zstd::stream::write::Encoder::new(writer, self.config.compression_level)?;
+        self.sequence += 1;
+        info!("started capture file {:?}", path);
+        Ok(CaptureFile {
+            encoder,
+            path,
+            bytes: 0,
+            opened: Instant::now(),
+        })
+    }
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(PARTIAL_SUFFIX);
+    PathBuf::from(partial)
+}
+
+/// Writes all broadcast updates until exit is signalled or the broadcast ends
+///
+/// Meant to run on its own thread, since writing files blocks. If the capture
+/// can't keep up, a Gap describing the lost messages is written before the
+/// next update for a slot.
+pub fn run_capture(
+    mut writer: CaptureWriter,
+    runtime: tokio::runtime::Handle,
+    mut receiver: broadcast::Receiver<Update>,
+    mut exit: broadcast::Receiver<()>,
+) {
+    // Slot of the latest update that was written
+    let mut last_slot = 0;
+
+    // Number of messages that were lost and not written in a Gap yet
+    let mut lost_messages = 0;
+
+    loop {
+        let update = runtime.block_on(async {
+            tokio::select! {
+                _ = exit.recv() => None,
+                update = receiver.recv() => Some(update),
+            }
+        });
+        let result = match update {
+            None | Some(Err(broadcast::error::RecvError::Closed)) => break,
+            Some(Err(broadcast::error::RecvError::Lagged(skipped))) => {
+                warn!(
+                    "capture lost {} messages, the file sink is too slow",
+                    skipped
+                );
+                CAPTURE_LAG_EVENTS_TOTAL.inc();
+                lost_messages += skipped;
+                continue;
+            }
+            Some(Ok(update)) => {
+                let slot = update_slot(&update);
+                let gap = match slot {
+                    Some(slot) if lost_messages > 0 => Some(Update {
+                        update_oneof: Some(UpdateOneof::Gap(Gap {
+                            lost_messages,
+                            first_slot: last_slot,
+                            last_slot: slot,
+                        })),
+                    }),
+                    _ => None,
+                };
+                if gap.is_some() {
+                    lost_messages = 0;
+                }
+                last_slot = slot.unwrap_or(last_slot);
+                gap.iter()
+                    .chain(std::iter::once(&update))
+                    .try_for_each(|update| writer.write(update))
+                    .and_then(|_| {
+                        // Pings arrive every few seconds, a good time to flush
+                        match update.update_oneof {
+                            Some(UpdateOneof::Ping(_)) => writer.flush(),
+                            _ => Ok(()),
+                        }
+                    })
+            }
+        };
+        if let Err(err) = result {
+            error!("writing capture file failed, stopping capture: {:?}", err);
+            return;
+        }
+    }
+    if let Err(err) = writer.finish() {
+        error!("finishing capture file failed: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {super::*, crate::geyser_plugin_grpc::geyser_proto::SlotUpdate};
+
+    fn read_capture_file(path: &Path) -> Vec<Update> {
+        let data = zstd::stream::decode_all(File::open(path).unwrap()).unwrap();
+        let mut buf = data.as_slice();
+        let mut updates = vec![];
+        while !buf.is_empty() {
+            updates.push(Update::decode_length_delimited(&mut buf).unwrap());
+        }
+        updates
+    }
+
+    #[test]
+    fn test_capture_rotation() {
+        let directory = std::env::temp_dir().join(format!(
+            "geyser-capture-test-{}-{}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let mut writer = CaptureWriter::new(CaptureConfig {
+            directory: directory.to_str().unwrap().to_string(),
+            max_file_bytes: 1,
+            max_file_age_secs: 0,
+            compression_level: 0,
+        })
+        .unwrap();
+        for slot in 0..3 {
+            writer
+                .write(&Update {
+                    update_oneof: Some(UpdateOneof::SlotUpdate(SlotUpdate {
+                        slot,
+                        parent: None,
+                        status: 0,
+                    })),
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut paths = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths.len(), 3);
+        let slots = paths
+            .iter()
+            .flat_map(|path| read_capture_file(path))
+            .map(|update| match update.update_oneof {
+                Some(UpdateOneof::SlotUpdate(slot_update)) => slot_update.slot,
+                _ => panic!("unexpected update"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(slots, vec![0, 1, 2]);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
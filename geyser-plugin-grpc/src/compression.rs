@@ -1,13 +1,71 @@
-use std::io::{Read, Write};
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+};
 
 pub fn zstd_compress(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0).unwrap();
+    let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0)?;
     encoder.write_all(data)?;
     encoder.finish()
 }
 
+/// Recently compressed data, so data sent to several subscribers is only
+/// compressed once
+///
+/// Keeps up to `capacity` entries, evicting the oldest ones first.
+#[derive(Debug)]
+pub struct CompressionCache<K> {
+    capacity: usize,
+    entries: Mutex<CacheEntries<K>>,
+}
+
+#[derive(Debug)]
+struct CacheEntries<K> {
+    data: HashMap<K, Arc<Vec<u8>>>,
+    /// Keys in the order they were inserted
+    order: VecDeque<K>,
+}
+
+impl<K: Clone + Eq + Hash> CompressionCache<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(CacheEntries {
+                data: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Compresses the data, unless data with the same key was compressed before
+    pub fn compress(&self, key: K, data: &[u8]) -> Result<Arc<Vec<u8>>, std::io::Error> {
+        if let Some(compressed) = self.entries.lock().unwrap().data.get(&key) {
+            return Ok(compressed.clone());
+        }
+
+        // Compress without holding the lock. Subscribers that need the same
+        // data at the same time may both compress it, which is harmless.
+        let compressed = Arc::new(zstd_compress(data)?);
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .data
+            .insert(key.clone(), compressed.clone())
+            .is_none()
+        {
+            entries.order.push_back(key);
+        }
+        while entries.order.len() > self.capacity {
+            let oldest = entries.order.pop_front().expect("not empty");
+            entries.data.remove(&oldest);
+        }
+        Ok(compressed)
+    }
+}
+
 pub fn zstd_decompress(data: &[u8], uncompressed: &mut Vec<u8>) -> Result<usize, std::io::Error> {
-    let mut decoder = zstd::stream::read::Decoder::new(data).unwrap();
+    let mut decoder = zstd::stream::read::Decoder::new(data)?;
     decoder.read_to_end(uncompressed)
 }
 
@@ -40,4 +98,22 @@ pub(crate) mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_compression_cache() {
+        let cache = CompressionCache::new(2);
+        let first = cache.compress(1, &[1; 100]).unwrap();
+        let mut decompressed = vec![];
+        zstd_decompress(&first, &mut decompressed).unwrap();
+        assert_eq!(decompressed, vec![1; 100]);
+
+        // the same key gets the same compressed data
+        assert!(Arc::ptr_eq(&first, &cache.compress(1, &[1; 100]).unwrap()));
+
+        // until it is evicted
+        cache.compress(2, &[2; 100]).unwrap();
+        cache.compress(3, &[3; 100]).unwrap();
+        assert!(!Arc::ptr_eq(&first, &cache.compress(1, &[1; 100]).unwrap()));
+        assert_eq!(cache.entries.lock().unwrap().data.len(), 2);
+    }
 }
@@ -1,53 +1,239 @@
 use {
     crate::{
-        accounts_selector::AccountsSelector,
-        compression::zstd_compress,
+        account_store::AccountStore,
+        accounts_selector::{reslice_data, slice_data, AccountFilter, AccountsSelector, DataSlice},
+        active_accounts::{ActiveAccounts, ActiveAccountsConfig},
+        auth::{AccessScope, AccessTokenConfig, AccessTokens},
+        capture::{run_capture, CaptureConfig, CaptureWriter},
+        compression::{zstd_compress, CompressionCache},
+        health::{report_health, HealthState},
         prom::{
-            PrometheusConfig, PrometheusService, BROADCAST_ACCOUNTS_TOTAL, BROADCAST_SLOTS_TOTAL,
-            SLOTS_LAST_PROCESSED,
+            PrometheusConfig, PrometheusService, BROADCAST_ACCOUNTS_TOTAL, BROADCAST_BATCHES_TOTAL,
+            BROADCAST_SLOTS_TOTAL, BROADCAST_TRANSACTIONS_TOTAL, SLOTS_LAST_PROCESSED,
+            SUBSCRIBER_BYTES_SENT_TOTAL, SUBSCRIBER_CONNECTION_AGE_SECONDS,
+            SUBSCRIBER_LAG_EVENTS_TOTAL, SUBSCRIBER_MESSAGES_SENT_TOTAL, SUBSCRIBER_QUEUE_DEPTH,
         },
+        replay_buffer::{update_slot, Replay, ReplayBuffer},
+        update_batch::{BatchConfig, PendingBatch},
+        version::VERSION,
     },
     geyser_proto::{
-        slot_update::Status as SlotUpdateStatus, update::UpdateOneof, AccountWrite, Ping,
-        SlotUpdate, SubscribeRequest, SubscribeResponse, Update, UpdateAccountsSelectorRequest,
-        UpdateAccountsSelectorResponse,
+        account_write::DataEncoding, slot_update::Status as SlotUpdateStatus, update::UpdateOneof,
+        AccountWrite, EndOfStartup, Gap, GetServerInfoRequest, GetServerInfoResponse,
+        GetSnapshotRequest, GetSnapshotResponse, Ping, SlotUpdate, SubscribeRequest,
+        SubscribeResponse, Transaction, Update, UpdateAccountsSelectorRequest,
+        UpdateAccountsSelectorResponse, UpdateBatch,
     },
     log::*,
     serde_derive::Deserialize,
     solana_geyser_plugin_interface::geyser_plugin_interface::{
-        GeyserPlugin, GeyserPluginError, ReplicaAccountInfoVersions, Result as PluginResult,
-        SlotStatus,
+        GeyserPlugin, GeyserPluginError, ReplicaAccountInfoVersions,
+        ReplicaTransactionInfoVersions, Result as PluginResult, SlotStatus,
     },
     std::{
-        collections::HashSet,
+        collections::VecDeque,
         convert::TryInto,
         fs::File,
         io::Read,
+        path::Path,
         sync::{
-            atomic::{AtomicU64, Ordering},
-            Arc, RwLock,
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc, Mutex, RwLock,
         },
+        thread::JoinHandle,
+        time::{Duration, Instant},
     },
     tokio::sync::{broadcast, mpsc},
-    tonic::transport::Server,
+    tonic::{
+        service::interceptor::InterceptedService,
+        transport::{Certificate, Identity, NamedService, Server, ServerTlsConfig},
+    },
+    tonic_health::ServingStatus,
 };
 
 pub mod geyser_proto {
     tonic::include_proto!("accountsdb");
 }
 
+fn proto_data_slices(slices: &[DataSlice]) -> Vec<geyser_proto::DataSlice> {
+    slices
+        .iter()
+        .map(|slice| geyser_proto::DataSlice {
+            offset: slice.offset as u64,
+            length: slice.length as u64,
+        })
+        .collect()
+}
+
+fn from_proto_data_slices(slices: &[geyser_proto::DataSlice]) -> Vec<DataSlice> {
+    slices
+        .iter()
+        .map(|slice| DataSlice {
+            offset: slice.offset as usize,
+            length: slice.length as usize,
+        })
+        .collect()
+}
+
+/// Replaces the data of the account write by the given slices of it
+///
+/// If the write was sliced already, only the parts of `slices` within the
+/// available slices are kept.
+fn slice_account_write(write: &mut AccountWrite, slices: &[DataSlice]) {
+    let (data, slices) = if write.data_slices.is_empty() {
+        slice_data(slices, &write.data)
+    } else {
+        reslice_data(
+            &from_proto_data_slices(&write.data_slices),
+            &write.data,
+            slices,
+        )
+    };
+    write.data = data;
+    write.data_slices = proto_data_slices(&slices);
+}
+
+/// Picks the encoding of account data sent to a client
+///
+/// Data is only compressed for clients that said they can decode it.
+fn negotiate_data_encoding(
+    zstd_compression: bool,
+    accepted_data_encodings: &[i32],
+) -> DataEncoding {
+    if zstd_compression && accepted_data_encodings.contains(&(DataEncoding::Zstd as i32)) {
+        DataEncoding::Zstd
+    } else {
+        DataEncoding::None
+    }
+}
+
+/// Identifies the data of an account write: pubkey, slot, write_version and
+/// the data slices it was reduced to
+pub type AccountDataKey = (Vec<u8>, u64, u64, Vec<(u64, u64)>);
+
+/// Encodes the data of account writes, including those in batches
+fn encode_account_data(
+    update: &mut Update,
+    data_encoding: DataEncoding,
+    cache: &CompressionCache<AccountDataKey>,
+) {
+    match update.update_oneof.as_mut() {
+        Some(UpdateOneof::AccountWrite(write)) => {
+            encode_account_write(write, data_encoding, Some(cache))
+        }
+        Some(UpdateOneof::Batch(batch)) => {
+            for update in batch.updates.iter_mut() {
+                encode_account_data(update, data_encoding, cache);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Encodes the data of an account write, reusing data compressed for other
+/// subscribers if there is a cache
+fn encode_account_write(
+    write: &mut AccountWrite,
+    data_encoding: DataEncoding,
+    cache: Option<&CompressionCache<AccountDataKey>>,
+) {
+    if data_encoding != DataEncoding::Zstd || write.data_encoding != DataEncoding::None as i32 {
+        return;
+    }
+    let compressed = match cache {
+        Some(cache) => {
+            let key = (
+                write.pubkey.clone(),
+                write.slot,
+                write.write_version,
+                write
+                    .data_slices
+                    .iter()
+                    .map(|slice| (slice.offset, slice.length))
+                    .collect(),
+            );
+            cache
+                .compress(key, &write.data)
+                .map(|data| data.as_ref().clone())
+        }
+        None => zstd_compress(&write.data),
+    };
+    match compressed {
+        Ok(data) => {
+            write.data = data;
+            write.data_encoding = DataEncoding::Zstd as i32;
+        }
+        Err(e) => {
+            warn!("zstd compression failed = {:?} , using original data.", e);
+        }
+    }
+}
+
 pub mod geyser_service {
     use super::*;
     use {
         geyser_proto::accounts_db_server::AccountsDb,
+        prost::Message,
         tokio_stream::wrappers::ReceiverStream,
         tonic::{Code, Request, Response, Status},
     };
 
+    /// What to do with subscribers that can't keep up with the broadcast
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum LagPolicy {
+        /// Close the subscriber stream
+        Disconnect,
+        /// Send a Gap update describing the lost updates and continue
+        Gap,
+    }
+
+    impl Default for LagPolicy {
+        fn default() -> Self {
+            LagPolicy::Disconnect
+        }
+    }
+
     #[derive(Clone, Debug, Deserialize)]
     pub struct ServiceConfig {
         broadcast_buffer_size: usize,
         subscriber_buffer_size: usize,
+        /// Number of recent updates kept for subscribers that resume from a slot
+        #[serde(default)]
+        replay_buffer_size: usize,
+        #[serde(default)]
+        lag_policy: LagPolicy,
+        /// Number of updates queued in memory per subscriber when its stream is
+        /// full, before it counts as lagging (0 to disable)
+        #[serde(default)]
+        subscriber_overflow_queue_size: usize,
+        /// Keep the latest rooted write of each selected account to serve GetSnapshot
+        #[serde(default)]
+        account_snapshots: bool,
+        /// Keep the startup account writes for subscribers that request them
+        ///
+        /// Requires a replay buffer, since they are only useful together with
+        /// all later updates. They are dropped once the replay buffer evicts
+        /// its first update.
+        #[serde(default)]
+        keep_startup_writes: bool,
+        /// Reject new subscribers beyond this many (0 for no limit)
+        #[serde(default)]
+        max_subscribers: usize,
+        /// Bounds the tracking of previously selected accounts per subscriber
+        /// that filters accounts
+        #[serde(default)]
+        subscriber_active_accounts: ActiveAccountsConfig,
+    }
+
+    impl ServiceConfig {
+        pub fn validate(&self) -> anyhow::Result<()> {
+            anyhow::ensure!(
+                !self.keep_startup_writes || self.replay_buffer_size > 0,
+                "keep_startup_writes requires a replay_buffer_size"
+            );
+            Ok(())
+        }
     }
 
     #[derive(Debug)]
@@ -56,6 +242,30 @@ pub mod geyser_service {
         pub config: ServiceConfig,
         pub highest_write_slot: Arc<AtomicU64>,
         pub accounts_selector: Arc<RwLock<AccountsSelector>>,
+
+        /// Must be locked while broadcasting, to keep it consistent with the
+        /// broadcast channel
+        pub replay_buffer: Arc<Mutex<ReplayBuffer>>,
+
+        /// Only set if account_snapshots is enabled
+        pub account_store: Option<Arc<RwLock<AccountStore>>>,
+
+        /// Accepted selector updates are persisted here, if set
+        pub accounts_selector_file: Option<String>,
+
+        /// Held while a selector update is persisted and applied, so the
+        /// persisted and the live selector can't end up different
+        pub accounts_selector_update_lock: Arc<Mutex<()>>,
+
+        /// Compress account data for clients that accept zstd
+        pub zstd_compression: bool,
+
+        /// Account data compressed for a subscriber, for reuse by the others
+        compression_cache: Arc<CompressionCache<AccountDataKey>>,
+
+        /// Number of connected subscribers, unlike the broadcast's receiver
+        /// count it excludes internal receivers like the capture
+        subscribers: Arc<AtomicUsize>,
     }
 
     impl Service {
@@ -63,54 +273,566 @@ pub mod geyser_service {
             config: ServiceConfig,
             highest_write_slot: Arc<AtomicU64>,
             accounts_selector: Arc<RwLock<AccountsSelector>>,
+            accounts_selector_file: Option<String>,
+            zstd_compression: bool,
         ) -> Self {
             let (tx, _) = broadcast::channel(config.broadcast_buffer_size);
+            let replay_buffer = Arc::new(Mutex::new(ReplayBuffer::new(
+                config.replay_buffer_size,
+                config.keep_startup_writes,
+            )));
+            // Subscribers that are further behind lag anyway
+            let compression_cache = Arc::new(CompressionCache::new(config.broadcast_buffer_size));
+            let account_store = config
+                .account_snapshots
+                .then(|| Arc::new(RwLock::new(AccountStore::default())));
             Self {
                 sender: tx,
                 config,
                 highest_write_slot,
                 accounts_selector,
+                replay_buffer,
+                account_store,
+                accounts_selector_file,
+                accounts_selector_update_lock: Arc::new(Mutex::new(())),
+                zstd_compression,
+                compression_cache,
+                subscribers: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        /// Persists the selector to the accounts_selector_file, if there is
+        /// one, and applies it
+        async fn apply_accounts_selector(
+            &self,
+            config: serde_json::Value,
+            selector: AccountsSelector,
+        ) -> Result<(), String> {
+            let update_lock = self.accounts_selector_update_lock.clone();
+            let accounts_selector = self.accounts_selector.clone();
+            let path = self.accounts_selector_file.clone();
+            tokio::task::spawn_blocking(move || {
+                store_accounts_selector(
+                    &update_lock,
+                    &accounts_selector,
+                    path.as_deref(),
+                    &config,
+                    selector,
+                )
+            })
+            .await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| format!("could not persist the accounts selector: {}", error))
+        }
+    }
+
+    /// Account filter requested by a single subscriber or imposed by its access token
+    ///
+    /// Applied on top of the global accounts selector, which bounds what
+    /// the plugin broadcasts at all. Updates must match all selectors.
+    pub(super) struct SubscriberFilter {
+        accounts_selectors: Vec<Arc<AccountsSelector>>,
+
+        /// Accounts that matched the filter before
+        ///
+        /// Needed to forward writes that signal account closure, where
+        /// lamports=0 and owner=system-program.
+        active_accounts: ActiveAccounts,
+
+        /// Parts of the account data the subscriber wants, all of it if empty
+        data_slices: Vec<DataSlice>,
+    }
+
+    impl SubscriberFilter {
+        pub(super) fn new(
+            accounts: &[String],
+            owners: &[String],
+            data_slices: &[geyser_proto::DataSlice],
+            scope: &AccessScope,
+            active_accounts: &ActiveAccountsConfig,
+        ) -> anyhow::Result<Option<Self>> {
+            let mut accounts_selectors = vec![];
+            if let Some(scope) = scope.0.as_ref() {
+                accounts_selectors.push(scope.clone());
+            }
+            if !accounts.is_empty() || !owners.is_empty() {
+                let accounts = accounts.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+                let owners = owners.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+                let accounts_selector = AccountsSelector::new(&accounts, &owners)?;
+                accounts_selectors.push(Arc::new(accounts_selector.into_restriction()));
+            }
+            let data_slices = from_proto_data_slices(data_slices);
+            if accounts_selectors.is_empty() && data_slices.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(Self {
+                accounts_selectors,
+                active_accounts: ActiveAccounts::for_subscriber(active_accounts.clone()),
+                data_slices,
+            }))
+        }
+
+        /// Reduces the account data to the slices the subscriber wants
+        fn slice(&self, write: &mut AccountWrite) {
+            if !self.data_slices.is_empty() {
+                slice_account_write(write, &self.data_slices);
+            }
+        }
+
+        /// Returns whether the account write matches all selectors
+        fn is_write_selected(&self, write: &AccountWrite) -> bool {
+            if write.data_slices.is_empty() {
+                return self.accounts_selectors.iter().all(|selector| {
+                    selector.is_account_selected(&write.pubkey, &write.owner, &write.data)
+                });
+            }
+            let available = from_proto_data_slices(&write.data_slices);
+            self.accounts_selectors.iter().all(|selector| {
+                selector.is_sliced_account_selected(
+                    &write.pubkey,
+                    &write.owner,
+                    &available,
+                    &write.data,
+                )
+            })
+        }
+
+        /// Returns whether the update should be forwarded to the subscriber
+        ///
+        /// Clears the is_selected flag of account writes that are only
+        /// forwarded because the account matched the filter previously, and
+        /// slices their data.
+        pub(super) fn filter(&mut self, update: &mut Update) -> bool {
+            let write = match update.update_oneof.as_mut() {
+                Some(UpdateOneof::AccountWrite(write)) => write,
+                Some(UpdateOneof::Batch(batch)) => {
+                    batch.updates = std::mem::take(&mut batch.updates)
+                        .into_iter()
+                        .filter_map(|mut update| self.filter(&mut update).then(|| update))
+                        .collect();
+                    return !batch.updates.is_empty();
+                }
+                Some(UpdateOneof::Transaction(transaction)) => {
+                    let account_keys = transaction
+                        .account_keys
+                        .iter()
+                        .map(|key| key.as_slice())
+                        .collect::<Vec<_>>();
+                    let active_accounts = &self.active_accounts;
+                    return self.accounts_selectors.iter().all(|selector| {
+                        selector.is_transaction_selected(
+                            transaction.is_vote,
+                            &account_keys,
+                            |key| active_accounts.contains(key),
+                        )
+                    });
+                }
+                _ => return true,
+            };
+            let pubkey: [u8; 32] = match write.pubkey.as_slice().try_into() {
+                Ok(pubkey) => pubkey,
+                Err(_) => return false,
+            };
+
+            let is_selected = self.is_write_selected(write);
+            if !is_selected && !self.active_accounts.contains(&pubkey) {
+                return false;
+            }
+            // Track the account, or stop tracking it if it was closed
+            self.active_accounts.on_write(pubkey, write.is_closed);
+
+            write.is_selected &= is_selected;
+
+            self.slice(write);
+            true
+        }
+    }
+
+    /// Counts a subscriber as connected for as long as it exists
+    struct SubscriberGuard(Arc<AtomicUsize>);
+
+    impl SubscriberGuard {
+        fn new(subscribers: Arc<AtomicUsize>) -> Self {
+            subscribers.fetch_add(1, Ordering::SeqCst);
+            Self(subscribers)
+        }
+    }
+
+    impl Drop for SubscriberGuard {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Prometheus metrics of a single subscriber, removed when it disconnects
+    struct SubscriberMetrics {
+        peer: String,
+        connected: Instant,
+    }
+
+    impl SubscriberMetrics {
+        fn new(peer: String) -> Self {
+            Self {
+                peer,
+                connected: Instant::now(),
+            }
+        }
+
+        fn on_sent(&self, bytes: usize, queue_depth: usize) {
+            let labels = &[self.peer.as_str()];
+            SUBSCRIBER_MESSAGES_SENT_TOTAL
+                .with_label_values(labels)
+                .inc();
+            SUBSCRIBER_BYTES_SENT_TOTAL
+                .with_label_values(labels)
+                .inc_by(bytes as u64);
+            SUBSCRIBER_QUEUE_DEPTH
+                .with_label_values(labels)
+                .set(queue_depth as i64);
+            SUBSCRIBER_CONNECTION_AGE_SECONDS
+                .with_label_values(labels)
+                .set(self.connected.elapsed().as_secs() as i64);
+        }
+
+        fn on_lag(&self) {
+            SUBSCRIBER_LAG_EVENTS_TOTAL
+                .with_label_values(&[self.peer.as_str()])
+                .inc();
+        }
+    }
+
+    impl Drop for SubscriberMetrics {
+        fn drop(&mut self) {
+            let labels = &[self.peer.as_str()];
+            // Metrics that were never set don't exist, ignore that error
+            let _ = SUBSCRIBER_MESSAGES_SENT_TOTAL.remove_label_values(labels);
+            let _ = SUBSCRIBER_BYTES_SENT_TOTAL.remove_label_values(labels);
+            let _ = SUBSCRIBER_QUEUE_DEPTH.remove_label_values(labels);
+            let _ = SUBSCRIBER_CONNECTION_AGE_SECONDS.remove_label_values(labels);
+            let _ = SUBSCRIBER_LAG_EVENTS_TOTAL.remove_label_values(labels);
+        }
+    }
+
+    enum Forwarded {
+        Ok,
+        Lagged,
+        Closed,
+    }
+
+    /// Sends an update to the subscriber stream
+    ///
+    /// If an overflow queue is configured, updates are queued instead of waiting
+    /// for the stream to have capacity.
+    async fn forward(
+        tx: &mpsc::Sender<Result<Update, Status>>,
+        overflow: &mut VecDeque<Update>,
+        overflow_limit: usize,
+        update: Update,
+    ) -> Forwarded {
+        if overflow_limit == 0 {
+            return match tx.send(Ok(update)).await {
+                Ok(()) => Forwarded::Ok,
+                Err(_) => Forwarded::Closed,
+            };
+        }
+
+        if overflow.is_empty() {
+            match tx.try_send(Ok(update)) {
+                Ok(()) => return Forwarded::Ok,
+                Err(mpsc::error::TrySendError::Full(update)) => {
+                    overflow.push_back(update.expect("only updates are queued"))
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => return Forwarded::Closed,
+            }
+        } else {
+            overflow.push_back(update);
+        }
+
+        if overflow.len() > overflow_limit {
+            Forwarded::Lagged
+        } else {
+            Forwarded::Ok
+        }
+    }
+
+    /// Forwards replayed and broadcast updates to a single subscriber
+    async fn forward_updates(
+        mut broadcast_rx: broadcast::Receiver<Update>,
+        tx: mpsc::Sender<Result<Update, Status>>,
+        replay: Replay,
+        mut filter: Option<SubscriberFilter>,
+        data_encoding: DataEncoding,
+        compression_cache: Arc<CompressionCache<AccountDataKey>>,
+        config: ServiceConfig,
+        metrics: SubscriberMetrics,
+        _subscriber: SubscriberGuard,
+    ) {
+        let overflow_limit = config.subscriber_overflow_queue_size;
+        let mut overflow = VecDeque::<Update>::new();
+
+        // Slot of the latest update that was forwarded
+        let mut last_slot = 0;
+
+        // Number of messages that were lost and not reported in a Gap yet. Like
+        // broadcast lag, this counts a batch of updates as one message.
+        let mut lost_messages = 0;
+        let mut lost_first_slot = 0;
+
+        let mut replay = replay.into_updates();
+        loop {
+            let update = tokio::select! {
+                permit = tx.reserve(), if !overflow.is_empty() => {
+                    match permit {
+                        Ok(permit) => permit.send(Ok(overflow.pop_front().expect("not empty"))),
+                        Err(_) => break,
+                    }
+                    continue;
+                },
+                update = async {
+                    match replay.next() {
+                        Some(update) => Ok(update),
+                        None => broadcast_rx.recv().await,
+                    }
+                } => update,
+            };
+
+            let mut update = match update {
+                Ok(update) => update,
+                Err(broadcast::error::RecvError::Lagged(count))
+                    if config.lag_policy == LagPolicy::Gap =>
+                {
+                    warn!("subscriber lagged, lost {} messages", count);
+                    metrics.on_lag();
+                    if lost_messages == 0 {
+                        lost_first_slot = last_slot;
+                    }
+                    lost_messages += count;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    // We couldn't keep up pulling from the broadcast channel,
+                    // close the connection because data was lost.
+                    warn!("subscriber lagged, lost {} messages, disconnecting", count);
+                    metrics.on_lag();
+                    let _ = tx
+                        .send(Err(Status::new(
+                            Code::DataLoss,
+                            format!("subscriber lagged, lost {} messages", count),
+                        )))
+                        .await;
+                    return;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            if let Some(filter) = filter.as_mut() {
+                if !filter.filter(&mut update) {
+                    continue;
+                }
+            }
+            encode_account_data(&mut update, data_encoding, &compression_cache);
+
+            let slot = update_slot(&update);
+            let mut updates = vec![];
+            if let (Some(slot), true) = (slot, lost_messages > 0) {
+                updates.push(Update {
+                    update_oneof: Some(UpdateOneof::Gap(Gap {
+                        lost_messages,
+                        first_slot: lost_first_slot,
+                        last_slot: slot,
+                    })),
+                });
+                lost_messages = 0;
+            }
+            last_slot = slot.unwrap_or(last_slot);
+            updates.push(update);
+
+            for update in updates {
+                let bytes = update.encoded_len();
+                match forward(&tx, &mut overflow, overflow_limit, update).await {
+                    Forwarded::Ok => {
+                        let queue_depth =
+                            config.subscriber_buffer_size - tx.capacity() + overflow.len();
+                        metrics.on_sent(bytes, queue_depth);
+                    }
+                    Forwarded::Closed => {
+                        info!("subscriber stream closed");
+                        return;
+                    }
+                    Forwarded::Lagged => {
+                        warn!("subscriber overflow queue is full");
+                        metrics.on_lag();
+                        if config.lag_policy == LagPolicy::Disconnect {
+                            // Waits for the stream to have capacity, so the
+                            // subscriber learns why it ends
+                            overflow.clear();
+                            let _ = tx
+                                .send(Err(Status::new(
+                                    Code::DataLoss,
+                                    "subscriber overflow queue is full",
+                                )))
+                                .await;
+                            return;
+                        }
+
+                        // Drop the queue, but keep track of what was lost, including
+                        // gaps that were queued
+                        let mut first_slot = if lost_messages == 0 {
+                            u64::MAX
+                        } else {
+                            lost_first_slot
+                        };
+                        for dropped in overflow.drain(..) {
+                            match &dropped.update_oneof {
+                                Some(UpdateOneof::Gap(gap)) => {
+                                    lost_messages += gap.lost_messages;
+                                    first_slot = first_slot.min(gap.first_slot);
+                                }
+                                _ => {
+                                    lost_messages += 1;
+                                    first_slot =
+                                        first_slot.min(update_slot(&dropped).unwrap_or(last_slot));
+                                }
+                            }
+                        }
+                        lost_first_slot = first_slot.min(last_slot);
+                    }
+                }
+            }
+        }
+        info!("subscriber stream closed");
+    }
+
+    /// Approximate size limit of a single GetSnapshotResponse, well below the
+    /// default message size limit of 4MB
+    const SNAPSHOT_CHUNK_BYTES: usize = 1024 * 1024;
+
+    /// Splits the snapshot into responses of bounded size
+    fn snapshot_chunks(slot: u64, accounts: Vec<AccountWrite>) -> Vec<GetSnapshotResponse> {
+        let mut chunks = vec![];
+        let mut chunk = GetSnapshotResponse {
+            slot,
+            accounts: vec![],
+        };
+        let mut chunk_bytes = 0;
+        for write in accounts {
+            let write_bytes = write.encoded_len();
+            if !chunk.accounts.is_empty() && chunk_bytes + write_bytes > SNAPSHOT_CHUNK_BYTES {
+                chunks.push(std::mem::replace(
+                    &mut chunk,
+                    GetSnapshotResponse {
+                        slot,
+                        accounts: vec![],
+                    },
+                ));
+                chunk_bytes = 0;
             }
+            chunk_bytes += write_bytes;
+            chunk.accounts.push(write);
+        }
+        if !chunk.accounts.is_empty() || chunks.is_empty() {
+            chunks.push(chunk);
         }
+        chunks
     }
 
     #[tonic::async_trait]
     impl AccountsDb for Service {
         type SubscribeStream = ReceiverStream<Result<Update, Status>>;
+        type GetSnapshotStream = ReceiverStream<Result<GetSnapshotResponse, Status>>;
 
         async fn subscribe(
             &self,
-            _request: Request<SubscribeRequest>,
+            request: Request<SubscribeRequest>,
         ) -> Result<Response<Self::SubscribeStream>, Status> {
-            info!("new subscriber");
+            let peer = request
+                .remote_addr()
+                .map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+            info!("new subscriber {}", peer);
+            let scope = request
+                .extensions()
+                .get::<AccessScope>()
+                .cloned()
+                .unwrap_or(AccessScope(None));
+            let filter = SubscriberFilter::new(
+                &request.get_ref().accounts,
+                &request.get_ref().owners,
+                &request.get_ref().data_slices,
+                &scope,
+                &self.config.subscriber_active_accounts,
+            )
+            .map_err(|err| Status::new(Code::InvalidArgument, err.to_string()))?;
+            let data_encoding = negotiate_data_encoding(
+                self.zstd_compression,
+                &request.get_ref().accepted_data_encodings,
+            );
+
             let (tx, rx) = mpsc::channel(self.config.subscriber_buffer_size);
-            let mut broadcast_rx = self.sender.subscribe();
+
+            // Subscribing while holding the replay buffer lock guarantees that the
+            // replayed updates and the live updates neither overlap nor have a gap.
+            let (broadcast_rx, replay, oldest_available_slot, startup_replay, subscriber) = {
+                let replay_buffer = self.replay_buffer.lock().unwrap();
+                let max_subscribers = self.config.max_subscribers;
+                if max_subscribers > 0 && self.subscribers.load(Ordering::SeqCst) >= max_subscribers
+                {
+                    warn!("rejecting subscriber {}, limit reached", peer);
+                    return Err(Status::new(
+                        Code::ResourceExhausted,
+                        format!("too many subscribers, limit is {}", max_subscribers),
+                    ));
+                }
+                let subscriber = SubscriberGuard::new(self.subscribers.clone());
+                let broadcast_rx = self.sender.subscribe();
+                let oldest_available_slot = replay_buffer.oldest_available_slot();
+                let startup_updates = if request.get_ref().include_startup {
+                    replay_buffer.startup_updates()
+                } else {
+                    None
+                };
+                let startup_replay = startup_updates.is_some();
+                let replay = match (
+                    startup_updates,
+                    request.get_ref().start_slot,
+                    oldest_available_slot,
+                ) {
+                    (Some(startup_updates), _, _) => startup_updates,
+                    (None, Some(start_slot), Some(oldest_slot)) if start_slot >= oldest_slot => {
+                        replay_buffer.updates_since(start_slot).into()
+                    }
+                    _ => Replay::default(),
+                };
+                (
+                    broadcast_rx,
+                    replay,
+                    oldest_available_slot,
+                    startup_replay,
+                    subscriber,
+                )
+            };
 
             tx.send(Ok(Update {
                 update_oneof: Some(UpdateOneof::SubscribeResponse(SubscribeResponse {
                     highest_write_slot: self.highest_write_slot.load(Ordering::SeqCst),
+                    oldest_available_slot,
+                    startup_replay,
+                    data_encoding: Some(data_encoding as i32),
                 })),
             }))
             .await
             .unwrap();
 
-            tokio::spawn(async move {
-                let mut exit = false;
-                while !exit {
-                    let fwd = broadcast_rx.recv().await.map_err(|err| {
-                        // Note: If we can't keep up pulling from the broadcast
-                        // channel here, there'll be a Lagged error, and we'll
-                        // close the connection because data was lost.
-                        warn!("error while receiving message to be broadcast: {:?}", err);
-                        exit = true;
-                        Status::new(Code::Internal, err.to_string())
-                    });
-                    if let Err(_err) = tx.send(fwd).await {
-                        info!("subscriber stream closed");
-                        exit = true;
-                    }
-                }
-            });
+            tokio::spawn(forward_updates(
+                broadcast_rx,
+                tx,
+                replay,
+                filter,
+                data_encoding,
+                self.compression_cache.clone(),
+                self.config.clone(),
+                SubscriberMetrics::new(peer),
+                subscriber,
+            ));
             Ok(Response::new(ReceiverStream::new(rx)))
         }
 
@@ -118,25 +840,114 @@ pub mod geyser_service {
             &self,
             request: Request<UpdateAccountsSelectorRequest>,
         ) -> Result<Response<geyser_proto::UpdateAccountsSelectorResponse>, Status> {
-            let (is_ok, error_message) =
-                match serde_json::from_str::<serde_json::Value>(&request.get_ref().config)
-                    .map_err(|error| error.to_string())
-                    .and_then(|config| {
-                        Plugin::create_accounts_selector_from_config(&config)
-                            .map_err(|error| error.to_string())
-                    }) {
-                    Ok(accounts_selector_new) => {
-                        *self.accounts_selector.write().unwrap() = accounts_selector_new;
-                        (true, String::new())
-                    }
-                    Err(error) => (false, error),
-                };
+            if let Some(AccessScope(Some(_))) = request.extensions().get::<AccessScope>() {
+                return Err(Status::new(
+                    Code::PermissionDenied,
+                    "access token is restricted to a set of accounts",
+                ));
+            }
+
+            let parsed = serde_json::from_str::<serde_json::Value>(&request.get_ref().config)
+                .map_err(|error| error.to_string())
+                .and_then(|config| {
+                    let accounts_selector = Plugin::create_accounts_selector_from_config(&config)
+                        .map_err(|error| error.to_string())?;
+                    Ok((config, accounts_selector))
+                });
+            let result = match parsed {
+                Ok((config, accounts_selector)) => {
+                    self.apply_accounts_selector(config, accounts_selector)
+                        .await
+                }
+                Err(error) => Err(error),
+            };
+            let (is_ok, error_message) = match result {
+                Ok(()) => (true, String::new()),
+                Err(error) => (false, error),
+            };
 
             Ok(Response::new(UpdateAccountsSelectorResponse {
                 is_ok,
                 error_message,
             }))
         }
+
+        async fn get_snapshot(
+            &self,
+            request: Request<GetSnapshotRequest>,
+        ) -> Result<Response<Self::GetSnapshotStream>, Status> {
+            let account_store = self.account_store.as_ref().ok_or_else(|| {
+                Status::new(Code::Unavailable, "account snapshots are not enabled")
+            })?;
+            let scope = request
+                .extensions()
+                .get::<AccessScope>()
+                .cloned()
+                .unwrap_or(AccessScope(None));
+            let filter = SubscriberFilter::new(
+                &request.get_ref().accounts,
+                &request.get_ref().owners,
+                &request.get_ref().data_slices,
+                &scope,
+                &self.config.subscriber_active_accounts,
+            )
+            .map_err(|err| Status::new(Code::InvalidArgument, err.to_string()))?;
+            let data_encoding = negotiate_data_encoding(
+                self.zstd_compression,
+                &request.get_ref().accepted_data_encodings,
+            );
+
+            // Slicing and compressing all accounts takes a while
+            let account_store = account_store.clone();
+            let (slot, accounts) = tokio::task::spawn_blocking(move || {
+                let (slot, mut accounts) = account_store.read().unwrap().snapshot(|write| {
+                    filter
+                        .as_ref()
+                        .map_or(true, |filter| filter.is_write_selected(write))
+                });
+                for write in accounts.iter_mut() {
+                    if let Some(filter) = filter.as_ref() {
+                        filter.slice(write);
+                    }
+                    // Not cached, snapshot data would evict the data of live updates
+                    encode_account_write(write, data_encoding, None);
+                }
+                (slot, accounts)
+            })
+            .await
+            .map_err(|err| Status::new(Code::Internal, err.to_string()))?;
+            info!(
+                "serving snapshot of {} accounts at slot {}",
+                accounts.len(),
+                slot
+            );
+
+            let chunks = snapshot_chunks(slot, accounts);
+            let (tx, rx) = mpsc::channel(4);
+            tokio::spawn(async move {
+                for chunk in chunks {
+                    if tx.send(Ok(chunk)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(Response::new(ReceiverStream::new(rx)))
+        }
+
+        async fn get_server_info(
+            &self,
+            _request: Request<GetServerInfoRequest>,
+        ) -> Result<Response<GetServerInfoResponse>, Status> {
+            let version = serde_json::to_string(&VERSION)
+                .map_err(|err| Status::new(Code::Internal, err.to_string()))?;
+            let accounts_selector = self.accounts_selector.read().unwrap().to_config();
+            Ok(Response::new(GetServerInfoResponse {
+                version,
+                accounts_selector: accounts_selector.to_string(),
+                highest_write_slot: self.highest_write_slot.load(Ordering::SeqCst),
+                subscribers: self.subscribers.load(Ordering::SeqCst) as u64,
+            }))
+        }
     }
 }
 
@@ -144,8 +955,15 @@ pub struct PluginData {
     runtime: tokio::runtime::Runtime,
     prometheus: PrometheusService,
     server_broadcast: broadcast::Sender<Update>,
+    replay_buffer: Arc<Mutex<ReplayBuffer>>,
+    /// Only set if batching is configured
+    pending_batch: Option<Arc<Mutex<PendingBatch>>>,
+    account_store: Option<Arc<RwLock<AccountStore>>>,
     server_exit_sender: broadcast::Sender<()>,
     accounts_selector: Arc<RwLock<AccountsSelector>>,
+    health: Arc<HealthState>,
+    /// Only set if capturing is configured
+    capture_thread: Option<JoinHandle<()>>,
 
     /// Largest slot that an account write was processed for
     highest_write_slot: Arc<AtomicU64>,
@@ -154,8 +972,7 @@ pub struct PluginData {
     ///
     /// Needed to catch writes that signal account closure, where
     /// lamports=0 and owner=system-program.
-    active_accounts: RwLock<HashSet<[u8; 32]>>,
-    zstd_compression: bool,
+    active_accounts: RwLock<ActiveAccounts>,
 }
 
 #[derive(Default)]
@@ -170,22 +987,230 @@ impl std::fmt::Debug for Plugin {
     }
 }
 
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// If set, clients must present a certificate signed by this CA
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct PluginConfig {
     pub bind_address: String,
     pub service_config: geyser_service::ServiceConfig,
+    /// Compress account data for clients that accept zstd
     pub zstd_compression: bool,
     #[serde(default)]
     pub prometheus: Option<PrometheusConfig>,
+    /// Clients must present one of these tokens, if any are configured
+    #[serde(default)]
+    pub access_tokens: Vec<AccessTokenConfig>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Broadcast account writes in batches instead of one by one
+    #[serde(default)]
+    pub batch: Option<BatchConfig>,
+    /// Bounds the tracking of previously selected accounts
+    #[serde(default)]
+    pub active_accounts: ActiveAccountsConfig,
+    /// Accepted UpdateAccountsSelector requests and config file reloads are written
+    /// to this file. If it exists, it takes precedence over accounts_selector when
+    /// the plugin is loaded.
+    #[serde(default)]
+    pub accounts_selector_file: Option<String>,
+    /// Check the config file for changes to accounts_selector this often (0 to disable)
+    #[serde(default)]
+    pub config_reload_interval_secs: u64,
+    /// Report NOT_SERVING to health checks if no new slot was seen for this long
+    #[serde(default = "default_slot_stall_timeout_secs")]
+    pub slot_stall_timeout_secs: u64,
+    /// Write all broadcast updates to capture files, for replaying them later
+    #[serde(default)]
+    pub capture: Option<CaptureConfig>,
+}
+
+fn default_slot_stall_timeout_secs() -> u64 {
+    30
+}
+
+fn make_tls_config(config: &TlsConfig) -> PluginResult<ServerTlsConfig> {
+    let read = |path: &str| {
+        std::fs::read(path).map_err(|err| GeyserPluginError::ConfigFileReadError {
+            msg: format!("Error reading {:?}: {:?}", path, err),
+        })
+    };
+    let identity = Identity::from_pem(read(&config.cert_path)?, read(&config.key_path)?);
+    let mut tls_config = ServerTlsConfig::new().identity(identity);
+    if let Some(client_ca_path) = &config.client_ca_path {
+        tls_config = tls_config.client_ca_root(Certificate::from_pem(read(client_ca_path)?));
+    }
+    Ok(tls_config)
+}
+
+/// Broadcasts updates, as a single UpdateBatch if there are several
+///
+/// The replay buffer keeps them individually.
+fn send_updates(
+    updates: Vec<Update>,
+    replay_buffer: &Mutex<ReplayBuffer>,
+    sender: &broadcast::Sender<Update>,
+) {
+    if updates.is_empty() {
+        return;
+    }
+    let mut replay_buffer = replay_buffer.lock().unwrap();
+    for update in &updates {
+        replay_buffer.push(update);
+    }
+    let update = if updates.len() == 1 {
+        updates.into_iter().next().expect("one update")
+    } else {
+        BROADCAST_BATCHES_TOTAL.inc();
+        Update {
+            update_oneof: Some(UpdateOneof::Batch(UpdateBatch { updates })),
+        }
+    };
+    // Don't care about the error that happens when there are no receivers.
+    let _ = sender.send(update);
+}
+
+/// Replaces the accounts selector file atomically
+fn write_accounts_selector_file(
+    path: &str,
+    accounts_selector: &serde_json::Value,
+) -> std::io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    std::fs::write(&tmp_path, serde_json::to_vec_pretty(accounts_selector)?)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// The persisted accounts selector if there is one, since it reflects the
+/// latest updates, otherwise the one from the config file
+fn initial_accounts_selector_config(
+    accounts_selector_file: Option<&str>,
+    config_file_selector: &serde_json::Value,
+) -> PluginResult<serde_json::Value> {
+    match accounts_selector_file {
+        Some(path) if Path::new(path).exists() => {
+            info!("Loading accounts selector from {:?}", path);
+            let contents = std::fs::read_to_string(path)?;
+            serde_json::from_str(&contents).map_err(|err| GeyserPluginError::ConfigFileReadError {
+                msg: format!("Error parsing the accounts_selector_file {:?}", err),
+            })
+        }
+        _ => Ok(config_file_selector.clone()),
+    }
+}
+
+fn read_accounts_selector_config(config_file: &str) -> anyhow::Result<serde_json::Value> {
+    let contents = std::fs::read_to_string(config_file)?;
+    let config: serde_json::Value = serde_json::from_str(&contents)?;
+    Ok(config["accounts_selector"].clone())
+}
+
+/// Persists a selector update to the accounts_selector_file, if there is one,
+/// and applies it
+///
+/// Only updates that survive a restart are applied. The update lock keeps
+/// concurrent updates from being persisted and applied in different orders.
+fn store_accounts_selector(
+    update_lock: &Mutex<()>,
+    accounts_selector: &RwLock<AccountsSelector>,
+    accounts_selector_file: Option<&str>,
+    config: &serde_json::Value,
+    selector: AccountsSelector,
+) -> std::io::Result<()> {
+    let _update_guard = update_lock.lock().unwrap();
+    if let Some(path) = accounts_selector_file {
+        write_accounts_selector_file(path, config)?;
+    }
+    *accounts_selector.write().unwrap() = selector;
+    Ok(())
+}
+
+/// Applies changes to accounts_selector in the config file
+///
+/// Only changes relative to the previous version of the file are applied, so
+/// selector updates through the RPC aren't reverted.
+fn reload_accounts_selector(
+    config_file: &str,
+    previous_config: &mut serde_json::Value,
+    update_lock: &Mutex<()>,
+    accounts_selector: &RwLock<AccountsSelector>,
+    accounts_selector_file: Option<&str>,
+) -> anyhow::Result<()> {
+    let selector_config = read_accounts_selector_config(config_file)?;
+    if selector_config == *previous_config {
+        return Ok(());
+    }
+    *previous_config = selector_config.clone();
+
+    info!("accounts_selector in the config file changed, reloading");
+    let selector = Plugin::create_accounts_selector_from_config(&selector_config)?;
+    store_accounts_selector(
+        update_lock,
+        accounts_selector,
+        accounts_selector_file,
+        &selector_config,
+        selector,
+    )?;
+    Ok(())
 }
 
 impl PluginData {
     fn broadcast(&self, update: UpdateOneof) {
-        // Don't care about the error that happens when there are no receivers.
-        let _ = self.server_broadcast.send(Update {
+        let write_slot = match &update {
+            UpdateOneof::AccountWrite(write) => Some(write.slot),
+            _ => None,
+        };
+        let update = Update {
             update_oneof: Some(update),
-        });
+        };
+        let pending_batch = match &self.pending_batch {
+            Some(pending_batch) => pending_batch,
+            None => {
+                send_updates(vec![update], &self.replay_buffer, &self.server_broadcast);
+                return;
+            }
+        };
+
+        // Only account writes for the same slot are batched. Holding the lock
+        // while sending keeps all updates in order.
+        let mut pending_batch = pending_batch.lock().unwrap();
+        match write_slot {
+            Some(slot) => {
+                if pending_batch
+                    .slot()
+                    .map_or(false, |batch_slot| batch_slot != slot)
+                {
+                    send_updates(
+                        pending_batch.take(),
+                        &self.replay_buffer,
+                        &self.server_broadcast,
+                    );
+                }
+                pending_batch.push(slot, update);
+                if pending_batch.is_full() {
+                    send_updates(
+                        pending_batch.take(),
+                        &self.replay_buffer,
+                        &self.server_broadcast,
+                    );
+                }
+            }
+            None => {
+                send_updates(
+                    pending_batch.take(),
+                    &self.replay_buffer,
+                    &self.server_broadcast,
+                );
+                send_updates(vec![update], &self.replay_buffer, &self.server_broadcast);
+            }
+        }
     }
 }
 
@@ -207,10 +1232,6 @@ impl GeyserPlugin for Plugin {
         file.read_to_string(&mut contents)?;
 
         let result: serde_json::Value = serde_json::from_str(&contents).unwrap();
-        let accounts_selector = Arc::new(RwLock::new(
-            Self::create_accounts_selector_from_config(&result["accounts_selector"]).unwrap(),
-        ));
-
         let config: PluginConfig = serde_json::from_str(&contents).map_err(|err| {
             GeyserPluginError::ConfigFileReadError {
                 msg: format!(
@@ -219,6 +1240,22 @@ impl GeyserPlugin for Plugin {
                 ),
             }
         })?;
+        config
+            .service_config
+            .validate()
+            .map_err(|err| GeyserPluginError::ConfigFileReadError {
+                msg: format!("Error in the service_config {:?}", err),
+            })?;
+
+        let selector_config = initial_accounts_selector_config(
+            config.accounts_selector_file.as_deref(),
+            &result["accounts_selector"],
+        )?;
+        let accounts_selector = Self::create_accounts_selector_from_config(&selector_config)
+            .map_err(|err| GeyserPluginError::ConfigFileReadError {
+                msg: format!("Error parsing the accounts_selector {:?}", err),
+            })?;
+        let accounts_selector = Arc::new(RwLock::new(accounts_selector));
 
         let runtime = tokio::runtime::Runtime::new().unwrap();
         let prometheus = PrometheusService::new(&runtime, config.prometheus);
@@ -236,20 +1273,66 @@ impl GeyserPlugin for Plugin {
             config.service_config,
             highest_write_slot.clone(),
             Arc::clone(&accounts_selector),
+            config.accounts_selector_file.clone(),
+            config.zstd_compression,
         );
 
         let (server_exit_sender, mut server_exit_receiver) = broadcast::channel::<()>(1);
         let server_broadcast = service.sender.clone();
+        let replay_buffer = service.replay_buffer.clone();
+        let account_store = service.account_store.clone();
+        let accounts_selector_update_lock = service.accounts_selector_update_lock.clone();
 
+        let access_tokens = AccessTokens::new(&config.access_tokens).map_err(|err| {
+            GeyserPluginError::ConfigFileReadError {
+                msg: format!("Error parsing the access_tokens {:?}", err),
+            }
+        })?;
         let server = geyser_proto::accounts_db_server::AccountsDbServer::new(service)
             .accept_gzip()
             .send_gzip();
-        runtime.spawn(Server::builder().add_service(server).serve_with_shutdown(
-            addr,
-            async move {
-                let _ = server_exit_receiver.recv().await;
-            },
+        let server = InterceptedService::new(server, move |request: tonic::Request<()>| {
+            access_tokens.check(request)
+        });
+
+        // Health checks need no access token
+        let (mut health_reporter, health_server) = tonic_health::server::health_reporter();
+        let service_name = <geyser_proto::accounts_db_server::AccountsDbServer<
+            geyser_service::Service,
+        > as NamedService>::NAME;
+        runtime.block_on(async {
+            health_reporter
+                .set_service_status("", ServingStatus::NotServing)
+                .await;
+            health_reporter
+                .set_service_status(service_name, ServingStatus::NotServing)
+                .await;
+        });
+        let health = Arc::new(HealthState::default());
+        runtime.spawn(report_health(
+            health_reporter,
+            service_name,
+            health.clone(),
+            Duration::from_secs(config.slot_stall_timeout_secs),
+            server_exit_sender.subscribe(),
         ));
+
+        let mut server_builder = Server::builder();
+        if let Some(tls) = &config.tls {
+            server_builder = server_builder
+                .tls_config(make_tls_config(tls)?)
+                .map_err(|err| GeyserPluginError::ConfigFileReadError {
+                    msg: format!("Error configuring tls {:?}", err),
+                })?;
+        }
+        runtime.spawn(
+            server_builder
+                .add_service(health_server)
+                .add_service(server)
+                .serve_with_shutdown(addr, async move {
+                    let _ = server_exit_receiver.recv().await;
+                }),
+        );
         let server_broadcast_c = server_broadcast.clone();
         let mut server_exit_receiver = server_exit_sender.subscribe();
         runtime.spawn(async move {
@@ -266,15 +1349,91 @@ impl GeyserPlugin for Plugin {
             }
         });
 
+        if config.config_reload_interval_secs > 0 {
+            let config_file = config_file.to_string();
+            let accounts_selector = accounts_selector.clone();
+            let accounts_selector_file = config.accounts_selector_file.clone();
+            let interval = Duration::from_secs(config.config_reload_interval_secs);
+            let mut previous_config = result["accounts_selector"].clone();
+            let mut server_exit_receiver = server_exit_sender.subscribe();
+            runtime.spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = server_exit_receiver.recv() => { break; },
+                        _ = tokio::time::sleep(interval) => {},
+                    }
+                    // Reading and writing files blocks
+                    let result = tokio::task::block_in_place(|| {
+                        reload_accounts_selector(
+                            &config_file,
+                            &mut previous_config,
+                            &accounts_selector_update_lock,
+                            &accounts_selector,
+                            accounts_selector_file.as_deref(),
+                        )
+                    });
+                    if let Err(err) = result {
+                        warn!("could not reload the accounts selector: {:?}", err);
+                    }
+                }
+            });
+        }
+
+        // Broadcast batches that didn't fill up in time
+        let pending_batch = config
+            .batch
+            .map(|batch| Arc::new(Mutex::new(PendingBatch::new(batch))));
+        if let Some(pending_batch) = pending_batch.clone() {
+            let replay_buffer = replay_buffer.clone();
+            let server_broadcast = server_broadcast.clone();
+            let mut server_exit_receiver = server_exit_sender.subscribe();
+            let interval =
+                (pending_batch.lock().unwrap().max_delay() / 2).max(Duration::from_micros(100));
+            runtime.spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = server_exit_receiver.recv() => { break; },
+                        _ = tokio::time::sleep(interval) => {},
+                    }
+                    let mut pending_batch = pending_batch.lock().unwrap();
+                    if pending_batch.is_due() {
+                        send_updates(pending_batch.take(), &replay_buffer, &server_broadcast);
+                    }
+                }
+            });
+        }
+
+        let capture_thread = config
+            .capture
+            .map(|capture| {
+                let writer = CaptureWriter::new(capture).map_err(|err| {
+                    GeyserPluginError::ConfigFileReadError {
+                        msg: format!("Error creating the capture directory {:?}", err),
+                    }
+                })?;
+                let runtime = runtime.handle().clone();
+                let receiver = server_broadcast.subscribe();
+                let exit = server_exit_sender.subscribe();
+                std::thread::Builder::new()
+                    .name("geyser-capture".into())
+                    .spawn(move || run_capture(writer, runtime, receiver, exit))
+                    .map_err(GeyserPluginError::from)
+            })
+            .transpose()?;
+
         self.data = Some(PluginData {
             runtime,
             prometheus,
             server_broadcast,
+            replay_buffer,
+            pending_batch,
+            account_store,
             server_exit_sender,
             accounts_selector,
+            health,
+            capture_thread,
             highest_write_slot,
-            active_accounts: RwLock::new(HashSet::new()),
-            zstd_compression: config.zstd_compression,
+            active_accounts: RwLock::new(ActiveAccounts::new(config.active_accounts)),
         });
 
         Ok(())
@@ -289,6 +1448,10 @@ impl GeyserPlugin for Plugin {
         data.server_exit_sender
             .send(())
             .expect("sending grpc server termination should succeed");
+        // Let the capture thread complete its current file
+        if let Some(capture_thread) = data.capture_thread {
+            let _ = capture_thread.join();
+        }
         data.runtime.shutdown_background();
     }
 
@@ -311,25 +1474,20 @@ impl GeyserPlugin for Plugin {
 
                 // Select only accounts configured to look at, plus writes to accounts
                 // that were previously selected (to catch closures and account reuse)
-                let is_selected = data
-                    .accounts_selector
-                    .read()
-                    .unwrap()
-                    .is_account_selected(account.pubkey, account.owner);
+                let (is_selected, data_slices) = {
+                    let selector = data.accounts_selector.read().unwrap();
+                    let is_selected =
+                        selector.is_account_selected(account.pubkey, account.owner, account.data);
+                    (is_selected, selector.data_slices.clone())
+                };
                 let previously_selected = {
                     let read = data.active_accounts.read().unwrap();
-                    read.contains(&account.pubkey[0..32])
+                    read.contains(account.pubkey)
                 };
                 if !is_selected && !previously_selected {
                     return Ok(());
                 }
 
-                // If the account is newly selected, add it
-                if !previously_selected {
-                    let mut write = data.active_accounts.write().unwrap();
-                    write.insert(account.pubkey.try_into().unwrap());
-                }
-
                 data.highest_write_slot.fetch_max(slot, Ordering::SeqCst);
 
                 debug!(
@@ -339,22 +1497,19 @@ impl GeyserPlugin for Plugin {
                     slot,
                 );
 
-                let mut account_data = account.data.to_vec();
-
-                //zstd compress if enabled.
-                if data.zstd_compression {
-                    match zstd_compress(&account_data) {
-                        Ok(res) => account_data = res,
-                        Err(e) => {
-                            println!(
-                                "zstd_decompress compression failed = {:?} , using original data.",
-                                e
-                            );
-                        }
-                    }
-                }
+                // Only the configured slices are broadcast, stored and captured.
+                // Compression and narrower slices happen per subscriber.
+                let (account_data, data_slices) = if data_slices.is_empty() {
+                    (account.data.to_vec(), vec![])
+                } else {
+                    let (sliced, copied) = slice_data(&data_slices, account.data);
+                    (sliced, proto_data_slices(&copied))
+                };
+
+                // Closed accounts are reset to the system program with no lamports
+                let is_closed = account.lamports == 0 && account.owner == [0u8; 32];
 
-                data.broadcast(UpdateOneof::AccountWrite(AccountWrite {
+                let write = AccountWrite {
                     slot,
                     is_startup,
                     write_version: account.write_version,
@@ -365,7 +1520,21 @@ impl GeyserPlugin for Plugin {
                     rent_epoch: account.rent_epoch,
                     data: account_data,
                     is_selected,
-                }));
+                    data_encoding: DataEncoding::None as i32,
+                    is_closed,
+                    data_slices,
+                };
+                if let Some(account_store) = &data.account_store {
+                    let pubkey = account.pubkey.try_into().unwrap();
+                    account_store.write().unwrap().update(pubkey, &write);
+                }
+                data.broadcast(UpdateOneof::AccountWrite(write));
+
+                // Track the account, or stop tracking it if it was closed
+                data.active_accounts
+                    .write()
+                    .unwrap()
+                    .on_write(account.pubkey.try_into().unwrap(), is_closed);
 
                 BROADCAST_ACCOUNTS_TOTAL.inc();
             }
@@ -383,6 +1552,18 @@ impl GeyserPlugin for Plugin {
         let data = self.data.as_ref().expect("plugin must be initialized");
         debug!("Updating slot {:?} at with status {:?}", slot, status);
 
+        data.health.highest_slot.fetch_max(slot, Ordering::SeqCst);
+
+        // Before broadcasting, so subscribers that see the root get snapshots
+        // for at least that slot
+        if let Some(account_store) = &data.account_store {
+            let mut account_store = account_store.write().unwrap();
+            account_store.on_slot(slot, parent);
+            if matches!(status, SlotStatus::Rooted) {
+                account_store.on_rooted(slot);
+            }
+        }
+
         let (status, label) = match status {
             SlotStatus::Processed => (SlotUpdateStatus::Processed, "processed"),
             SlotStatus::Confirmed => (SlotUpdateStatus::Confirmed, "confirmed"),
@@ -404,8 +1585,70 @@ impl GeyserPlugin for Plugin {
     }
 
     fn notify_end_of_startup(&mut self) -> PluginResult<()> {
+        let data = self.data.as_ref().expect("plugin must be initialized");
+        let slot = data.highest_write_slot.load(Ordering::SeqCst);
+        info!("end of startup at slot {}", slot);
+        data.broadcast(UpdateOneof::EndOfStartup(EndOfStartup { slot }));
+        data.health.end_of_startup.store(true, Ordering::SeqCst);
         Ok(())
     }
+
+    fn notify_transaction(
+        &mut self,
+        transaction: ReplicaTransactionInfoVersions,
+        slot: u64,
+    ) -> PluginResult<()> {
+        let data = self.data.as_ref().expect("plugin must be initialized");
+        match transaction {
+            ReplicaTransactionInfoVersions::V0_0_1(transaction) => {
+                let account_keys = transaction.transaction.message().account_keys();
+                let account_keys = account_keys
+                    .iter()
+                    .map(|key| key.as_ref())
+                    .collect::<Vec<&[u8]>>();
+
+                let is_selected = {
+                    let active_accounts = data.active_accounts.read().unwrap();
+                    data.accounts_selector
+                        .read()
+                        .unwrap()
+                        .is_transaction_selected(transaction.is_vote, &account_keys, |key| {
+                            active_accounts.contains(key)
+                        })
+                };
+                if !is_selected {
+                    return Ok(());
+                }
+
+                debug!(
+                    "Updating transaction {:?} at slot {:?}",
+                    transaction.signature, slot
+                );
+
+                let meta = transaction.transaction_status_meta;
+                data.broadcast(UpdateOneof::Transaction(Transaction {
+                    slot,
+                    signature: transaction.signature.as_ref().to_vec(),
+                    is_vote: transaction.is_vote,
+                    error: meta.status.as_ref().err().map(|err| err.to_string()),
+                    account_keys: account_keys.iter().map(|key| key.to_vec()).collect(),
+                    log_messages: meta.log_messages.clone().unwrap_or_default(),
+                }));
+
+                BROADCAST_TRANSACTIONS_TOTAL.inc();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn transaction_notifications_enabled(&self) -> bool {
+        // Only queried by the validator on load, enabling transactions through
+        // UpdateAccountsSelector later has no effect if this returned false.
+        self.data.as_ref().map_or(false, |data| {
+            data.accounts_selector.read().unwrap().select_transactions
+        })
+    }
 }
 
 impl Plugin {
@@ -447,7 +1690,39 @@ impl Plugin {
                 .transpose()?
                 .unwrap_or_default();
 
-            AccountsSelector::new(&accounts, &owners)?
+            let filters = &accounts_selector["filters"];
+            let filters = filters
+                .as_array()
+                .map(|filters| {
+                    filters
+                        .iter()
+                        .map(AccountFilter::from_config)
+                        .collect::<anyhow::Result<Vec<_>>>()
+                })
+                .transpose()?
+                .unwrap_or_default();
+
+            let data_slices = &accounts_selector["data_slices"];
+            let data_slices = data_slices
+                .as_array()
+                .map(|data_slices| {
+                    data_slices
+                        .iter()
+                        .map(DataSlice::from_config)
+                        .collect::<anyhow::Result<Vec<_>>>()
+                })
+                .transpose()?
+                .unwrap_or_default();
+
+            let mut selector = AccountsSelector::new(&accounts, &owners)?;
+            selector.filters = filters;
+            selector.data_slices = data_slices;
+            selector.select_transactions =
+                accounts_selector["transactions"].as_bool().unwrap_or(false);
+            selector.select_vote_transactions = accounts_selector["vote_transactions"]
+                .as_bool()
+                .unwrap_or(false);
+            selector
         })
     }
 }
@@ -476,4 +1751,201 @@ pub(crate) mod tests {
         let config: serde_json::Value = serde_json::from_str(config).unwrap();
         Plugin::create_accounts_selector_from_config(&config["accounts_selector"]).unwrap();
     }
+
+    #[test]
+    fn test_accounts_selector_filters_from_config() {
+        let config = "{\"accounts_selector\" : { \
+           \"owners\" : [\"TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA\"], \
+           \"filters\" : [ \
+               {\"dataSize\": 165}, \
+               {\"memcmp\": {\"offset\": 0, \"bytes\": \"So11111111111111111111111111111111111111112\"}}, \
+               \"tokenAccountState\" \
+           ] \
+        }}";
+
+        let config: serde_json::Value = serde_json::from_str(config).unwrap();
+        let selector =
+            Plugin::create_accounts_selector_from_config(&config["accounts_selector"]).unwrap();
+        assert_eq!(selector.filters.len(), 3);
+        assert_eq!(selector.filters[0], AccountFilter::DataSize(165));
+
+        let roundtrip =
+            Plugin::create_accounts_selector_from_config(&selector.to_config()).unwrap();
+        assert_eq!(roundtrip.owners, selector.owners);
+        assert_eq!(roundtrip.filters, selector.filters);
+    }
+
+    #[test]
+    fn test_accounts_selector_persist_and_reload() {
+        let directory = std::env::temp_dir().join(format!(
+            "geyser-selector-test-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let config_file = directory.join("config.json");
+        let config_file = config_file.to_str().unwrap();
+        let selector_file = directory.join("accounts_selector.json");
+        let selector_file = selector_file.to_str().unwrap();
+        let write_config = |owner: &str| {
+            let config = serde_json::json!({ "accounts_selector": { "owners": [owner] } });
+            std::fs::write(config_file, config.to_string()).unwrap();
+        };
+        let owner = |owner: &str| bs58::decode(owner).into_vec().unwrap();
+        let serum = "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin";
+        let token = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+
+        write_config(serum);
+        let mut previous_config = read_accounts_selector_config(config_file).unwrap();
+        let selector_config =
+            initial_accounts_selector_config(Some(selector_file), &previous_config).unwrap();
+        assert_eq!(selector_config, previous_config);
+        let accounts_selector =
+            RwLock::new(Plugin::create_accounts_selector_from_config(&selector_config).unwrap());
+        let update_lock = Mutex::new(());
+
+        // an unchanged config file isn't applied or persisted
+        reload_accounts_selector(
+            config_file,
+            &mut previous_config,
+            &update_lock,
+            &accounts_selector,
+            Some(selector_file),
+        )
+        .unwrap();
+        assert!(!Path::new(selector_file).exists());
+
+        write_config(token);
+        reload_accounts_selector(
+            config_file,
+            &mut previous_config,
+            &update_lock,
+            &accounts_selector,
+            Some(selector_file),
+        )
+        .unwrap();
+        assert!(accounts_selector
+            .read()
+            .unwrap()
+            .owners
+            .contains(&owner(token)));
+
+        // after a restart, the persisted selector wins over the config file
+        write_config(serum);
+        let config_file_selector = read_accounts_selector_config(config_file).unwrap();
+        let selector_config =
+            initial_accounts_selector_config(Some(selector_file), &config_file_selector).unwrap();
+        let selector = Plugin::create_accounts_selector_from_config(&selector_config).unwrap();
+        assert_eq!(selector.owners, accounts_selector.read().unwrap().owners);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_subscriber_filter_active_accounts() {
+        let owner = vec![2; 32];
+        let mut filter = geyser_service::SubscriberFilter::new(
+            &[],
+            &[bs58::encode(&owner).into_string()],
+            &[],
+            &AccessScope(None),
+            &ActiveAccountsConfig {
+                max_size: 1,
+                evict_closed: true,
+            },
+        )
+        .unwrap()
+        .unwrap();
+        let mut filter_write = |pubkey: u8, owner: &[u8], is_closed: bool| {
+            let mut update = Update {
+                update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                    pubkey: vec![pubkey; 32],
+                    owner: owner.to_vec(),
+                    lamports: if is_closed { 0 } else { 1 },
+                    is_selected: true,
+                    is_closed,
+                    ..AccountWrite::default()
+                })),
+            };
+            if !filter.filter(&mut update) {
+                return None;
+            }
+            match update.update_oneof {
+                Some(UpdateOneof::AccountWrite(write)) => Some(write.is_selected),
+                _ => panic!("expected an account write"),
+            }
+        };
+        let system_program = [0; 32];
+
+        // account 1 is evicted by account 2, so its closure isn't forwarded
+        assert_eq!(filter_write(1, &owner, false), Some(true));
+        assert_eq!(filter_write(2, &owner, false), Some(true));
+        assert_eq!(filter_write(1, &system_program, true), None);
+
+        // the closure of a tracked account is forwarded, but not selected
+        assert_eq!(filter_write(2, &system_program, true), Some(false));
+        // closed accounts aren't tracked anymore
+        assert_eq!(filter_write(2, &system_program, false), None);
+
+        // accounts are tracked again once they match
+        assert_eq!(filter_write(1, &owner, false), Some(true));
+        assert_eq!(filter_write(1, &system_program, true), Some(false));
+    }
+
+    #[test]
+    fn test_slice_account_write() {
+        let slice = |offset, length| DataSlice { offset, length };
+        let mut write = AccountWrite {
+            data: (0..10).collect(),
+            ..AccountWrite::default()
+        };
+
+        // sliced by the accounts selector when the plugin receives the write
+        slice_account_write(&mut write, &[slice(2, 4)]);
+        assert_eq!(write.data, vec![2, 3, 4, 5]);
+        assert_eq!(write.data_slices, proto_data_slices(&[slice(2, 4)]));
+
+        // subscribers can only narrow the available slices
+        slice_account_write(&mut write, &[slice(0, 3), slice(5, 3)]);
+        assert_eq!(write.data, vec![2, 5]);
+        assert_eq!(
+            write.data_slices,
+            proto_data_slices(&[slice(2, 1), slice(5, 1)])
+        );
+    }
+
+    #[test]
+    fn test_data_encoding_negotiation() {
+        let zstd = DataEncoding::Zstd as i32;
+        assert_eq!(negotiate_data_encoding(true, &[zstd]), DataEncoding::Zstd);
+        assert_eq!(negotiate_data_encoding(true, &[]), DataEncoding::None);
+        assert_eq!(negotiate_data_encoding(false, &[zstd]), DataEncoding::None);
+
+        let write = AccountWrite {
+            data: vec![7; 100],
+            ..AccountWrite::default()
+        };
+        let mut update = Update {
+            update_oneof: Some(UpdateOneof::Batch(UpdateBatch {
+                updates: vec![Update {
+                    update_oneof: Some(UpdateOneof::AccountWrite(write.clone())),
+                }],
+            })),
+        };
+        encode_account_data(&mut update, DataEncoding::Zstd, &CompressionCache::new(1));
+        let encoded = match update.update_oneof {
+            Some(UpdateOneof::Batch(mut batch)) => match batch.updates.remove(0).update_oneof {
+                Some(UpdateOneof::AccountWrite(write)) => write,
+                _ => panic!("expected an account write"),
+            },
+            _ => panic!("expected a batch"),
+        };
+        assert_eq!(encoded.data_encoding, DataEncoding::Zstd as i32);
+        let mut data = vec![];
+        crate::compression::zstd_decompress(&encoded.data, &mut data).unwrap();
+        assert_eq!(data, write.data);
+    }
 }
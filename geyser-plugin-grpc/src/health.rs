@@ -0,0 +1,67 @@
+use {
+    log::*,
+    std::{
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::sync::broadcast,
+    tonic_health::{server::HealthReporter, ServingStatus},
+};
+
+/// State the health of the plugin is derived from
+#[derive(Debug, Default)]
+pub struct HealthState {
+    /// Set once the validator has finished loading its snapshot
+    pub end_of_startup: AtomicBool,
+
+    /// Highest slot seen in a slot status update
+    pub highest_slot: AtomicU64,
+}
+
+/// Keeps the health status of `service_name` and of the server as a whole up to date
+///
+/// Reports NOT_SERVING until startup finishes and whenever no new slot was seen
+/// for `slot_stall_timeout`.
+pub async fn report_health(
+    mut reporter: HealthReporter,
+    service_name: &'static str,
+    state: Arc<HealthState>,
+    slot_stall_timeout: Duration,
+    mut exit: broadcast::Receiver<()>,
+) {
+    let mut highest_slot = 0;
+    let mut highest_slot_time = Instant::now();
+    let mut reported = None;
+    loop {
+        let slot = state.highest_slot.load(Ordering::SeqCst);
+        if slot != highest_slot {
+            highest_slot = slot;
+            highest_slot_time = Instant::now();
+        }
+
+        let is_serving = state.end_of_startup.load(Ordering::SeqCst)
+            && highest_slot_time.elapsed() < slot_stall_timeout;
+        if reported != Some(is_serving) {
+            let status = if is_serving {
+                ServingStatus::Serving
+            } else {
+                ServingStatus::NotServing
+            };
+            info!(
+                "health status is now {:?}, highest slot {}",
+                status, highest_slot
+            );
+            reporter.set_service_status("", status).await;
+            reporter.set_service_status(service_name, status).await;
+            reported = Some(is_serving);
+        }
+
+        tokio::select! {
+            _ = exit.recv() => { break; },
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {},
+        }
+    }
+}
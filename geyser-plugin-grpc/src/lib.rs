@@ -1,5 +1,12 @@
+pub mod account_store;
 pub mod accounts_selector;
+pub mod active_accounts;
+pub mod auth;
+pub mod capture;
 pub mod compression;
 pub mod geyser_plugin_grpc;
+pub mod health;
 pub mod prom;
+pub mod replay_buffer;
+pub mod update_batch;
 pub mod version;
@@ -7,7 +7,7 @@ use {
         Body, Request, Response, Server, StatusCode,
     },
     log::*,
-    prometheus::{IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder},
+    prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder},
     serde::Deserialize,
     std::{net::SocketAddr, sync::Once},
     tokio::{runtime::Runtime, sync::oneshot},
@@ -32,7 +32,61 @@ lazy_static::lazy_static! {
     ).unwrap();
 
     pub static ref BROADCAST_ACCOUNTS_TOTAL: IntCounter = IntCounter::new(
-        "broadcast_slots_total", "Total number of broadcasted slot messages",
+        "broadcast_accounts_total", "Total number of broadcasted account messages",
+    ).unwrap();
+
+    pub static ref BROADCAST_TRANSACTIONS_TOTAL: IntCounter = IntCounter::new(
+        "broadcast_transactions_total", "Total number of broadcasted transaction messages",
+    ).unwrap();
+
+    pub static ref BROADCAST_BATCHES_TOTAL: IntCounter = IntCounter::new(
+        "broadcast_batches_total", "Total number of broadcasted update batches",
+    ).unwrap();
+
+    pub static ref ACTIVE_ACCOUNTS: IntGauge = IntGauge::new(
+        "active_accounts", "Number of tracked previously selected accounts",
+    ).unwrap();
+
+    pub static ref ACTIVE_ACCOUNTS_EVICTIONS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("active_accounts_evictions_total", "Total number of accounts that stopped being tracked"),
+        &["reason"]
+    ).unwrap();
+
+    pub static ref SUBSCRIBER_QUEUE_DEPTH: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("subscriber_queue_depth", "Number of updates queued for the subscriber stream"),
+        &["peer"]
+    ).unwrap();
+
+    pub static ref SUBSCRIBER_MESSAGES_SENT_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("subscriber_messages_sent_total", "Total number of updates sent to the subscriber"),
+        &["peer"]
+    ).unwrap();
+
+    pub static ref SUBSCRIBER_BYTES_SENT_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("subscriber_bytes_sent_total", "Total encoded size of updates sent to the subscriber"),
+        &["peer"]
+    ).unwrap();
+
+    pub static ref SUBSCRIBER_LAG_EVENTS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("subscriber_lag_events_total", "Total number of times the subscriber lost updates"),
+        &["peer"]
+    ).unwrap();
+
+    pub static ref SUBSCRIBER_CONNECTION_AGE_SECONDS: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("subscriber_connection_age_seconds", "Time since the subscriber connected"),
+        &["peer"]
+    ).unwrap();
+
+    pub static ref CAPTURE_UPDATES_TOTAL: IntCounter = IntCounter::new(
+        "capture_updates_total", "Total number of updates written to capture files",
+    ).unwrap();
+
+    pub static ref CAPTURE_BYTES_TOTAL: IntCounter = IntCounter::new(
+        "capture_bytes_total", "Total uncompressed size of updates written to capture files",
+    ).unwrap();
+
+    pub static ref CAPTURE_LAG_EVENTS_TOTAL: IntCounter = IntCounter::new(
+        "capture_lag_events_total", "Total number of times capturing lost updates",
     ).unwrap();
 }
 
@@ -63,6 +117,18 @@ impl PrometheusService {
             register!(SLOTS_LAST_PROCESSED);
             register!(BROADCAST_SLOTS_TOTAL);
             register!(BROADCAST_ACCOUNTS_TOTAL);
+            register!(BROADCAST_TRANSACTIONS_TOTAL);
+            register!(BROADCAST_BATCHES_TOTAL);
+            register!(ACTIVE_ACCOUNTS);
+            register!(ACTIVE_ACCOUNTS_EVICTIONS_TOTAL);
+            register!(SUBSCRIBER_QUEUE_DEPTH);
+            register!(SUBSCRIBER_MESSAGES_SENT_TOTAL);
+            register!(SUBSCRIBER_BYTES_SENT_TOTAL);
+            register!(SUBSCRIBER_LAG_EVENTS_TOTAL);
+            register!(SUBSCRIBER_CONNECTION_AGE_SECONDS);
+            register!(CAPTURE_UPDATES_TOTAL);
+            register!(CAPTURE_BYTES_TOTAL);
+            register!(CAPTURE_LAG_EVENTS_TOTAL);
 
             for (key, value) in &[
                 ("version", VERSION_INFO.version),
@@ -0,0 +1,263 @@
+use {
+    crate::geyser_plugin_grpc::geyser_proto::{update::UpdateOneof, Update},
+    log::*,
+    std::{collections::VecDeque, sync::Arc},
+};
+
+/// Bounded buffer of recently broadcast updates
+///
+/// Allows subscribers that reconnect to replay the updates they missed
+/// instead of requesting a new snapshot.
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    updates: VecDeque<(u64, Update)>,
+    capacity: usize,
+
+    /// Slot of the first update that was ever pushed
+    first_slot: Option<u64>,
+
+    /// Highest slot that had updates evicted from the buffer
+    max_evicted_slot: Option<u64>,
+
+    /// Startup account writes and the EndOfStartup update, if they are kept
+    ///
+    /// Dropped once any update is evicted from the buffer, since they no
+    /// longer form a snapshot together with the buffer then.
+    startup: Option<StartupUpdates>,
+}
+
+#[derive(Debug, Default)]
+struct StartupUpdates {
+    /// Chunks are shared with subscribers, so they are never cloned while
+    /// locked. A new chunk is started once the last one is shared.
+    chunks: Vec<Arc<Vec<Update>>>,
+    is_complete: bool,
+}
+
+impl StartupUpdates {
+    fn push(&mut self, update: &Update) {
+        match self.chunks.last_mut().and_then(Arc::get_mut) {
+            Some(chunk) => chunk.push(update.clone()),
+            None => self.chunks.push(Arc::new(vec![update.clone()])),
+        }
+    }
+}
+
+/// Updates a subscriber gets before the live updates
+#[derive(Debug, Default)]
+pub struct Replay {
+    startup: Vec<Arc<Vec<Update>>>,
+    updates: Vec<Update>,
+}
+
+impl From<Vec<Update>> for Replay {
+    fn from(updates: Vec<Update>) -> Self {
+        Self {
+            startup: vec![],
+            updates,
+        }
+    }
+}
+
+impl Replay {
+    /// Clones the startup updates one by one, outside of the buffer's lock
+    pub fn into_updates(self) -> impl Iterator<Item = Update> + Send {
+        self.startup
+            .into_iter()
+            .flat_map(|chunk| (0..chunk.len()).map(move |i| chunk[i].clone()))
+            .chain(self.updates)
+    }
+}
+
+/// Slot an update is for, if any
+pub fn update_slot(update: &Update) -> Option<u64> {
+    match update.update_oneof.as_ref()? {
+        UpdateOneof::AccountWrite(write) => Some(write.slot),
+        UpdateOneof::SlotUpdate(slot_update) => Some(slot_update.slot),
+        UpdateOneof::Transaction(transaction) => Some(transaction.slot),
+        UpdateOneof::Batch(batch) => batch.updates.first().and_then(update_slot),
+        UpdateOneof::Ping(_)
+        | UpdateOneof::SubscribeResponse(_)
+        | UpdateOneof::Gap(_)
+        | UpdateOneof::EndOfStartup(_) => None,
+    }
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize, keep_startup_writes: bool) -> Self {
+        Self {
+            updates: VecDeque::with_capacity(capacity),
+            capacity,
+            first_slot: None,
+            max_evicted_slot: None,
+            startup: keep_startup_writes.then(StartupUpdates::default),
+        }
+    }
+
+    pub fn push(&mut self, update: &Update) {
+        match update.update_oneof.as_ref() {
+            Some(UpdateOneof::AccountWrite(write)) if write.is_startup => {
+                if let Some(startup) = self.startup.as_mut() {
+                    if !startup.is_complete {
+                        startup.push(update);
+                    }
+                }
+                return;
+            }
+            Some(UpdateOneof::EndOfStartup(_)) => {
+                if let Some(startup) = self.startup.as_mut() {
+                    startup.push(update);
+                    startup.is_complete = true;
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        let slot = match update_slot(update) {
+            Some(slot) => slot,
+            None => return,
+        };
+        if self.capacity == 0 {
+            self.drop_startup();
+            return;
+        }
+        self.first_slot.get_or_insert(slot);
+
+        while self.updates.len() >= self.capacity {
+            if let Some((evicted_slot, _)) = self.updates.pop_front() {
+                self.max_evicted_slot = self.max_evicted_slot.max(Some(evicted_slot));
+                self.drop_startup();
+            }
+        }
+        self.updates.push_back((slot, update.clone()));
+    }
+
+    fn drop_startup(&mut self) {
+        if self.startup.take().is_some() {
+            info!("dropping startup account writes, later updates are no longer available");
+        }
+    }
+
+    /// The startup account writes followed by all later buffered updates
+    ///
+    /// Returns None if the startup writes aren't kept or not all updates since
+    /// are available anymore. If startup hasn't finished yet, the remaining
+    /// startup writes and EndOfStartup are broadcast later.
+    pub fn startup_updates(&self) -> Option<Replay> {
+        let startup = self.startup.as_ref()?;
+        Some(Replay {
+            startup: startup.chunks.clone(),
+            updates: self
+                .updates
+                .iter()
+                .map(|(_, update)| update.clone())
+                .collect(),
+        })
+    }
+
+    /// Oldest slot for which all updates are still available
+    ///
+    /// The first slot that was seen may be incomplete, so it is excluded.
+    pub fn oldest_available_slot(&self) -> Option<u64> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let first_slot = self.first_slot?;
+        Some(self.max_evicted_slot.unwrap_or(first_slot).max(first_slot) + 1)
+    }
+
+    /// Clones all buffered updates for slots >= start_slot, in broadcast order
+    pub fn updates_since(&self, start_slot: u64) -> Vec<Update> {
+        self.updates
+            .iter()
+            .filter(|(slot, _)| *slot >= start_slot)
+            .map(|(_, update)| update.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        crate::geyser_plugin_grpc::geyser_proto::{AccountWrite, EndOfStartup, Ping, SlotUpdate},
+    };
+
+    fn slot_update(slot: u64) -> Update {
+        Update {
+            update_oneof: Some(UpdateOneof::SlotUpdate(SlotUpdate {
+                slot,
+                parent: None,
+                status: 0,
+            })),
+        }
+    }
+
+    #[test]
+    fn test_replay_buffer_eviction() {
+        let mut buffer = ReplayBuffer::new(3, false);
+        assert_eq!(buffer.oldest_available_slot(), None);
+
+        buffer.push(&slot_update(10));
+        buffer.push(&Update {
+            update_oneof: Some(UpdateOneof::Ping(Ping {})),
+        });
+        buffer.push(&slot_update(11));
+        assert_eq!(buffer.oldest_available_slot(), Some(11));
+        assert_eq!(buffer.updates_since(0).len(), 2);
+
+        buffer.push(&slot_update(12));
+        buffer.push(&slot_update(13));
+        buffer.push(&slot_update(14));
+        assert_eq!(buffer.oldest_available_slot(), Some(12));
+        assert_eq!(buffer.updates_since(13).len(), 2);
+    }
+
+    #[test]
+    fn test_replay_buffer_startup() {
+        let startup_write = Update {
+            update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                slot: 5,
+                is_startup: true,
+                ..Default::default()
+            })),
+        };
+        let end_of_startup = Update {
+            update_oneof: Some(UpdateOneof::EndOfStartup(EndOfStartup { slot: 5 })),
+        };
+
+        let startup_len = |buffer: &ReplayBuffer| {
+            buffer
+                .startup_updates()
+                .map(|replay| replay.into_updates().count())
+        };
+
+        let mut buffer = ReplayBuffer::new(2, true);
+        buffer.push(&startup_write);
+        buffer.push(&startup_write);
+        assert_eq!(startup_len(&buffer), Some(2));
+
+        // a subscriber keeps its replay while more startup writes arrive
+        let replay = buffer.startup_updates().unwrap();
+        buffer.push(&startup_write);
+        buffer.push(&end_of_startup);
+        assert_eq!(replay.into_updates().count(), 2);
+
+        buffer.push(&slot_update(6));
+        buffer.push(&slot_update(7));
+        assert_eq!(startup_len(&buffer), Some(6));
+
+        // once an update after startup is evicted, the startup writes are useless
+        buffer.push(&slot_update(8));
+        assert_eq!(startup_len(&buffer), None);
+
+        // evictions before the end of startup also invalidate them
+        let mut buffer = ReplayBuffer::new(1, true);
+        buffer.push(&startup_write);
+        buffer.push(&slot_update(4));
+        buffer.push(&slot_update(5));
+        buffer.push(&end_of_startup);
+        assert_eq!(startup_len(&buffer), None);
+    }
+}
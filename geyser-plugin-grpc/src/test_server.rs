@@ -6,7 +6,8 @@ pub mod geyser_proto {
     tonic::include_proto!("accountsdb");
 }
 use geyser_proto::{
-    update::UpdateOneof, SlotUpdate, SubscribeRequest, Update, UpdateAccountsSelectorRequest,
+    update::UpdateOneof, GetServerInfoRequest, GetServerInfoResponse, GetSnapshotRequest,
+    GetSnapshotResponse, SlotUpdate, SubscribeRequest, Update, UpdateAccountsSelectorRequest,
     UpdateAccountsSelectorResponse,
 };
 
@@ -33,6 +34,7 @@ pub mod geyser_service {
     #[tonic::async_trait]
     impl AccountsDb for Service {
         type SubscribeStream = ReceiverStream<Result<Update, Status>>;
+        type GetSnapshotStream = ReceiverStream<Result<GetSnapshotResponse, Status>>;
 
         async fn subscribe(
             &self,
@@ -59,6 +61,23 @@ pub mod geyser_service {
                 error_message: String::new(),
             }))
         }
+
+        async fn get_snapshot(
+            &self,
+            _request: Request<GetSnapshotRequest>,
+        ) -> Result<Response<Self::GetSnapshotStream>, Status> {
+            Err(Status::unimplemented("the test server has no accounts"))
+        }
+
+        async fn get_server_info(
+            &self,
+            _request: Request<GetServerInfoRequest>,
+        ) -> Result<Response<GetServerInfoResponse>, Status> {
+            Ok(Response::new(GetServerInfoResponse {
+                subscribers: self.sender.receiver_count() as u64,
+                ..Default::default()
+            }))
+        }
     }
 }
 
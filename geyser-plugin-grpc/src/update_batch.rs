@@ -0,0 +1,63 @@
+use {
+    crate::geyser_plugin_grpc::geyser_proto::Update,
+    serde_derive::Deserialize,
+    std::time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchConfig {
+    /// Maximum number of account writes per batch
+    pub max_updates: usize,
+    /// Maximum time an account write waits for its batch to fill up
+    pub max_delay_us: u64,
+}
+
+/// Account writes for a single slot that wait to be broadcast together
+#[derive(Debug)]
+pub struct PendingBatch {
+    config: BatchConfig,
+    updates: Vec<Update>,
+    slot: u64,
+    started: Instant,
+}
+
+impl PendingBatch {
+    pub fn new(config: BatchConfig) -> Self {
+        Self {
+            config,
+            updates: Vec::with_capacity(config.max_updates),
+            slot: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_micros(self.config.max_delay_us)
+    }
+
+    /// Slot of the pending writes, None if there are none
+    pub fn slot(&self) -> Option<u64> {
+        (!self.updates.is_empty()).then(|| self.slot)
+    }
+
+    pub fn push(&mut self, slot: u64, update: Update) {
+        if self.updates.is_empty() {
+            self.slot = slot;
+            self.started = Instant::now();
+        }
+        self.updates.push(update);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.updates.len() >= self.config.max_updates
+    }
+
+    pub fn is_due(&self) -> bool {
+        !self.updates.is_empty() && self.started.elapsed() >= self.max_delay()
+    }
+
+    pub fn take(&mut self) -> Vec<Update> {
+        std::mem::take(&mut self.updates)
+    }
+}
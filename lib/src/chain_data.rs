@@ -21,6 +21,8 @@ pub struct SlotData {
 pub struct AccountData {
     pub slot: u64,
     pub write_version: u64,
+    /// The write closed the account
+    pub is_closed: bool,
     pub account: AccountSharedData,
 }
 
@@ -114,6 +116,13 @@ impl ChainData {
                     .retain(|w| w.slot == newest_rooted_write || w.slot > self.newest_rooted_slot);
             }
 
+            // forget accounts that are left with nothing but a rooted closing write
+            self.accounts.retain(|_, writes| {
+                !(writes.len() == 1
+                    && writes[0].is_closed
+                    && writes[0].slot <= self.newest_rooted_slot)
+            });
+
             // now it's fine to drop any slots before the new rooted head
             // as account writes for non-rooted slots before it have been dropped
             self.slots.retain(|s, _| *s >= self.newest_rooted_slot);
@@ -159,6 +168,8 @@ impl ChainData {
     }
 
     /// Cloned snapshot of all the most recent live writes per pubkey
+    ///
+    /// Accounts whose most recent live write closed them are left out.
     pub fn accounts_snapshot(&self) -> HashMap<Pubkey, AccountData> {
         self.accounts
             .iter()
@@ -167,6 +178,9 @@ impl ChainData {
                     .iter()
                     .rev()
                     .find(|w| self.is_account_write_live(w))?;
+                if latest_good_write.is_closed {
+                    return None;
+                }
                 Some((*pubkey, latest_good_write.clone()))
             })
             .collect()
@@ -180,6 +194,7 @@ impl ChainData {
             .iter()
             .rev()
             .find(|w| self.is_account_write_live(w))
+            .filter(|w| !w.is_closed)
             .ok_or_else(|| anyhow::anyhow!("account {} has no live data", pubkey))
     }
 }
@@ -0,0 +1,7 @@
+use std::io::Read;
+
+/// Same as the geyser plugin's zstd_decompress(), for data it compressed
+pub fn zstd_decompress(data: &[u8], uncompressed: &mut Vec<u8>) -> Result<usize, std::io::Error> {
+    let mut decoder = zstd::stream::read::Decoder::new(data)?;
+    decoder.read_to_end(uncompressed)
+}
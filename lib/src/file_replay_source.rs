@@ -0,0 +1,428 @@
+use async_trait::async_trait;
+use prost::Message;
+
+use log::*;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::{
+    grpc_plugin_source::{account_write_from_proto, geyser_proto, slot_update_from_proto},
+    recorder::{segment_slots, PARTIAL_SUFFIX},
+    source::{Source, SourceContext, SourceMessage, WriteVersions},
+    FileReplaySourceConfig, ReplayPacing, SlotStatus,
+};
+
+/// Nominal duration of a slot
+///
+/// Captures don't record when updates were sent, so pacing is derived from
+/// the slot numbers of slot updates.
+const SLOT_DURATION: Duration = Duration::from_millis(400);
+
+/// Larger slot gaps, like between captures, aren't waited out
+const MAX_PACED_SLOT_GAP: u64 = 10;
+
+/// Longer length prefixes mean the capture is corrupt
+///
+/// Far above the largest account, leaving room for batches and the snapshots
+/// written by the recorder.
+const MAX_UPDATE_LENGTH: usize = 1 << 30;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Lists the capture files to replay, in order
+///
+/// Directories are expanded to the files in them sorted by name, which is the
+/// order the geyser plugin and the recorder write them in. Recorded segments
+/// that end before start_slot are skipped.
+fn capture_files(paths: &[String], start_slot: Option<u64>) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for path in paths {
+        let path = Path::new(path);
+        if !path.is_dir() {
+            files.push(path.to_path_buf());
+            continue;
+        }
+        let mut dir_files = std::fs::read_dir(path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        dir_files
+            .retain(|file| file.is_file() && !file.to_string_lossy().ends_with(PARTIAL_SUFFIX));
+        dir_files.retain(|file| {
+            let segment_end = segment_slots(file).map(|(_, last_slot)| last_slot);
+            !matches!((segment_end, start_slot), (Some(end), Some(start)) if end < start)
+        });
+        dir_files.sort();
+        files.extend(dir_files);
+    }
+    Ok(files)
+}
+
+/// Opens a capture file, decompressing it if it is zstd compressed
+fn open_capture(path: &Path) -> anyhow::Result<Box<dyn Read>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let is_zstd = file.fill_buf()?.starts_with(&ZSTD_MAGIC);
+    Ok(if is_zstd {
+        Box::new(zstd::stream::read::Decoder::with_buffer(file)?)
+    } else {
+        Box::new(file)
+    })
+}
+
+/// Reads the next length-delimited update, None at the end of the capture
+fn read_update(reader: &mut impl Read) -> anyhow::Result<Option<geyser_proto::Update>> {
+    // The length is a varint of at most 10 bytes, its last byte is < 0x80
+    let mut prefix = Vec::with_capacity(10);
+    loop {
+        let mut byte = [0u8; 1];
+        match reader.read_exact(&mut byte) {
+            Ok(()) => prefix.push(byte[0]),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof && prefix.is_empty() => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.into()),
+        }
+        if byte[0] < 0x80 || prefix.len() == 10 {
+            break;
+        }
+    }
+    let length = prost::decode_length_delimiter(prefix.as_slice())?;
+    anyhow::ensure!(
+        length <= MAX_UPDATE_LENGTH,
+        "update length {} exceeds the maximum of {}, the capture is corrupt",
+        length,
+        MAX_UPDATE_LENGTH
+    );
+    let mut buf = vec![0; length];
+    reader.read_exact(&mut buf)?;
+    Ok(Some(geyser_proto::Update::decode(buf.as_slice())?))
+}
+
+fn is_in_slot_range(slot: u64, start_slot: Option<u64>, end_slot: Option<u64>) -> bool {
+    start_slot.map_or(true, |start| slot >= start) && end_slot.map_or(true, |end| slot <= end)
+}
+
+/// Reads all captures and sends the account writes and slot updates in the slot range
+///
+/// Blocks, meant to run on a blocking thread.
+fn read_captures(
+    files: &[PathBuf],
+    start_slot: Option<u64>,
+    end_slot: Option<u64>,
+    sender: async_channel::Sender<geyser_proto::Update>,
+) -> anyhow::Result<()> {
+    use geyser_proto::update::UpdateOneof;
+    for path in files {
+        info!("replaying capture file {:?}", path);
+        let mut reader = open_capture(path)?;
+        loop {
+            let update = match read_update(&mut reader) {
+                Ok(Some(update)) => update,
+                Ok(None) => break,
+                Err(err) => {
+                    // Files of a crashed validator end in a partial update
+                    warn!("capture file {:?} is truncated: {:?}", path, err);
+                    break;
+                }
+            };
+            let updates = match update.update_oneof {
+                Some(UpdateOneof::Batch(batch)) => batch.updates,
+                _ => vec![update],
+            };
+            for update in updates {
+                let slot = match &update.update_oneof {
+                    Some(UpdateOneof::AccountWrite(write)) => write.slot,
+                    Some(UpdateOneof::SlotUpdate(slot_update)) => {
+                        // Once a later slot is rooted, all updates in the range were seen
+                        let is_rooted =
+                            slot_update.status == geyser_proto::slot_update::Status::Rooted as i32;
+                        if is_rooted && end_slot.map_or(false, |end| slot_update.slot > end) {
+                            return Ok(());
+                        }
+                        slot_update.slot
+                    }
+                    Some(UpdateOneof::Gap(gap)) => {
+                        let overlaps_range = start_slot
+                            .map_or(true, |start| gap.last_slot >= start)
+                            && end_slot.map_or(true, |end| gap.first_slot <= end);
+                        if overlaps_range {
+                            warn!(
+                                "capture lost {} messages for slots {} to {}",
+                                gap.lost_messages, gap.first_slot, gap.last_slot
+                            );
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if !is_in_slot_range(slot, start_slot, end_slot) {
+                    continue;
+                }
+                if futures::executor::block_on(sender.send(update)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replays captures of the geyser plugin's update stream
+///
+/// Writes are numbered per slot and pubkey like the grpc source does, since
+/// plugin captures carry the validator's write_version, which other sources
+/// can't match.
+pub struct FileReplaySource {
+    config: FileReplaySourceConfig,
+}
+
+impl FileReplaySource {
+    pub fn new(config: FileReplaySourceConfig) -> anyhow::Result<Self> {
+        if let ReplayPacing::Accelerated { speed } = config.pacing {
+            anyhow::ensure!(
+                speed > 0.0 && speed.is_finite(),
+                "replay speed must be positive, got {}",
+                speed
+            );
+        }
+        Ok(Self { config })
+    }
+}
+
+#[async_trait]
+impl Source for FileReplaySource {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Returns once all captures were replayed
+    async fn run(&self, context: SourceContext) {
+        replay(&self.config, context).await
+    }
+}
+
+async fn replay(config: &FileReplaySourceConfig, context: SourceContext) {
+    let SourceContext {
+        sender,
+        metrics: metrics_sender,
+        ..
+    } = context;
+    let files = match capture_files(&config.paths, config.start_slot) {
+        Ok(files) => files,
+        Err(err) => {
+            error!("could not list capture files: {:?}", err);
+            return;
+        }
+    };
+    info!("replaying {} capture files", files.len());
+
+    let (update_sender, update_receiver) = async_channel::bounded(1000);
+    let (start_slot, end_slot) = (config.start_slot, config.end_slot);
+    let reader = tokio::task::spawn_blocking(move || {
+        read_captures(&files, start_slot, end_slot, update_sender)
+    });
+
+    let slot_duration = match config.pacing {
+        ReplayPacing::Realtime => Some(SLOT_DURATION),
+        ReplayPacing::Accelerated { speed } => Some(SLOT_DURATION.div_f64(speed)),
+        ReplayPacing::Unpaced => None,
+    };
+    // Newest slot and the time it was replayed at
+    let mut paced_slot: Option<(u64, Instant)> = None;
+
+    let name = &config.name;
+    let mut metric_account_writes =
+        metrics_sender.register_u64(format!("replay_source_{}_account_writes", name));
+    let mut metric_slot_updates =
+        metrics_sender.register_u64(format!("replay_source_{}_slot_updates", name));
+    let mut metric_slot = metrics_sender.register_u64(format!("replay_source_{}_slot", name));
+
+    let mut write_versions = WriteVersions::default();
+
+    while let Ok(update) = update_receiver.recv().await {
+        match update.update_oneof {
+            Some(geyser_proto::update::UpdateOneof::AccountWrite(update)) => {
+                let (pubkey, slot) = (update.pubkey.clone(), update.slot);
+                let mut account_write = match account_write_from_proto(update) {
+                    Ok(account_write) => account_write,
+                    Err(err) => {
+                        error!(
+                            "could not decode data of account {} at slot {}: {:?}",
+                            bs58::encode(pubkey).into_string(),
+                            slot,
+                            err
+                        );
+                        continue;
+                    }
+                };
+                // Recorded snapshots keep write_version 0
+                if account_write.write_version != 0 {
+                    account_write.write_version =
+                        write_versions.next(account_write.slot, account_write.pubkey);
+                }
+                metric_account_writes.increment();
+                sender
+                    .send(SourceMessage::AccountWrite(account_write))
+                    .await
+                    .expect("send success");
+            }
+            Some(geyser_proto::update::UpdateOneof::SlotUpdate(update)) => {
+                if let Some(slot_duration) = slot_duration {
+                    match paced_slot {
+                        None => paced_slot = Some((update.slot, Instant::now())),
+                        Some((slot, time)) if update.slot > slot => {
+                            let gap = (update.slot - slot).min(MAX_PACED_SLOT_GAP);
+                            let time = time + slot_duration * gap as u32;
+                            tokio::time::sleep_until(time).await;
+                            paced_slot = Some((update.slot, time));
+                        }
+                        Some(_) => {}
+                    }
+                }
+
+                let slot_update = match slot_update_from_proto(&update) {
+                    Some(slot_update) => slot_update,
+                    None => {
+                        error!("unexpected slot status: {}", update.status);
+                        continue;
+                    }
+                };
+                if slot_update.status == SlotStatus::Rooted {
+                    write_versions.on_rooted(slot_update.slot);
+                }
+                metric_slot_updates.increment();
+                metric_slot.set_max(slot_update.slot);
+                sender
+                    .send(SourceMessage::SlotUpdate(slot_update))
+                    .await
+                    .expect("send success");
+            }
+            _ => {}
+        }
+    }
+
+    match reader.await {
+        Ok(Ok(())) => info!("replay finished"),
+        Ok(Err(err)) => error!("replay failed: {:?}", err),
+        Err(err) => error!("replay reader panicked: {:?}", err),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use geyser_proto::{update::UpdateOneof, AccountWrite, SlotUpdate, Update, UpdateBatch};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn slot_update(slot: u64, status: geyser_proto::slot_update::Status) -> Update {
+        Update {
+            update_oneof: Some(UpdateOneof::SlotUpdate(SlotUpdate {
+                slot,
+                parent: None,
+                status: status as i32,
+            })),
+        }
+    }
+
+    fn account_write(slot: u64) -> Update {
+        Update {
+            update_oneof: Some(UpdateOneof::AccountWrite(AccountWrite {
+                slot,
+                pubkey: vec![1; 32],
+                owner: vec![2; 32],
+                ..AccountWrite::default()
+            })),
+        }
+    }
+
+    fn encode(updates: &[Update]) -> Vec<u8> {
+        let mut data = vec![];
+        for update in updates {
+            update.encode_length_delimited(&mut data).unwrap();
+        }
+        data
+    }
+
+    fn update_slot(update: &Update) -> u64 {
+        match &update.update_oneof {
+            Some(UpdateOneof::AccountWrite(write)) => write.slot,
+            Some(UpdateOneof::SlotUpdate(slot_update)) => slot_update.slot,
+            _ => panic!("unexpected update"),
+        }
+    }
+
+    #[test]
+    fn test_read_update() {
+        use geyser_proto::slot_update::Status;
+        let updates = vec![slot_update(1, Status::Processed), account_write(1)];
+        let data = encode(&updates);
+
+        let mut reader = data.as_slice();
+        assert_eq!(read_update(&mut reader).unwrap(), Some(updates[0].clone()));
+        assert_eq!(read_update(&mut reader).unwrap(), Some(updates[1].clone()));
+        assert_eq!(read_update(&mut reader).unwrap(), None);
+
+        // A partial update is an error, not the end of the capture
+        let mut reader = &data[..data.len() - 1];
+        assert!(read_update(&mut reader).unwrap().is_some());
+        assert!(read_update(&mut reader).is_err());
+
+        // A corrupt length prefix is an error, without allocating for it
+        let mut data = vec![];
+        prost::encode_length_delimiter(MAX_UPDATE_LENGTH + 1, &mut data).unwrap();
+        assert!(read_update(&mut data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_read_captures() {
+        use geyser_proto::slot_update::Status;
+        let directory = std::env::temp_dir().join(format!(
+            "replay-test-{}-{}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        // A compressed capture that ends in a partial update
+        let mut data = encode(&[
+            slot_update(1, Status::Processed),
+            account_write(1),
+            Update {
+                update_oneof: Some(UpdateOneof::Batch(UpdateBatch {
+                    updates: vec![account_write(2), account_write(3)],
+                })),
+            },
+        ]);
+        let partial = encode(&[account_write(3)]);
+        data.extend_from_slice(&partial[..partial.len() / 2]);
+        let data = zstd::stream::encode_all(data.as_slice(), 0).unwrap();
+        std::fs::write(directory.join("a.bin"), data).unwrap();
+
+        // Reading stops once a slot after the range is rooted
+        let data = encode(&[
+            slot_update(3, Status::Confirmed),
+            slot_update(4, Status::Rooted),
+            account_write(4),
+        ]);
+        std::fs::write(directory.join("b.bin"), data).unwrap();
+
+        let files = capture_files(&[directory.to_str().unwrap().to_string()], None).unwrap();
+        assert_eq!(files.len(), 2);
+        let (sender, receiver) = async_channel::unbounded();
+        read_captures(&files, Some(2), Some(3), sender).unwrap();
+        let mut slots = vec![];
+        while let Ok(update) = receiver.try_recv() {
+            slots.push(update_slot(&update));
+        }
+        assert_eq!(slots, vec![2, 3, 3]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
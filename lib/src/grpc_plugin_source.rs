@@ -1,17 +1,29 @@
+use async_trait::async_trait;
 use jsonrpc_core::futures::StreamExt;
 use jsonrpc_core_client::transports::http;
 
 use solana_account_decoder::UiAccountEncoding;
 use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
-use solana_client::rpc_response::{Response, RpcKeyedAccount};
 use solana_rpc::{rpc::rpc_accounts::AccountsDataClient, rpc::OptionalContext};
 use solana_sdk::{account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey};
 
-use futures::{future, future::FutureExt};
-use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+use futures::{
+    future,
+    future::{FusedFuture, FutureExt},
+};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    transport::{Certificate, ClientTlsConfig, Endpoint, Identity},
+};
 
 use log::*;
-use std::{collections::HashMap, str::FromStr, time::Duration};
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
 
 pub mod geyser_proto {
     tonic::include_proto!("accountsdb");
@@ -19,56 +31,147 @@ pub mod geyser_proto {
 use geyser_proto::accounts_db_client::AccountsDbClient;
 
 use crate::{
-    metrics, AccountWrite, AnyhowWrap, GrpcSourceConfig, SlotStatus, SlotUpdate,
-    SnapshotSourceConfig, SourceConfig, TlsConfig,
+    compression,
+    recorder::Recorder,
+    snapshot_archive::{self, ArchiveSelection},
+    source::{SnapshotData, SnapshotMetrics, Source, SourceContext, SourceMessage},
+    AccountWrite, AnyhowWrap, DataSlice, GrpcSourceConfig, RecordConfig, SlotStatus, SlotUpdate,
+    SnapshotSourceConfig, TlsConfig,
 };
 
-type SnapshotData = Response<Vec<RpcKeyedAccount>>;
-
-enum Message {
-    GrpcUpdate(geyser_proto::Update),
-    Snapshot(SnapshotData),
-}
-
-async fn get_snapshot(
-    rpc_http_url: String,
-    program_id: Pubkey,
-) -> anyhow::Result<OptionalContext<Vec<RpcKeyedAccount>>> {
-    let rpc_client = http::connect_with_options::<AccountsDataClient>(&rpc_http_url, true)
+/// Number of accounts per getMultipleAccounts request, the RPC maximum
+const MULTIPLE_ACCOUNTS_CHUNK_SIZE: usize = 100;
+
+/// Requests the configured programs and accounts from the RPC node
+///
+/// The context slot of the first response is the minimum context slot for all
+/// further requests, so no part of the snapshot is older. All accounts are
+/// tagged with that slot.
+pub(crate) async fn get_snapshot(
+    config: SnapshotSourceConfig,
+    commitment: CommitmentConfig,
+) -> anyhow::Result<SnapshotData> {
+    let programs = config.programs()?;
+    let pubkeys = config.account_pubkeys()?;
+    let rpc_client = http::connect_with_options::<AccountsDataClient>(&config.rpc_http_url, true)
         .await
         .map_err_anyhow()?;
 
-    let account_info_config = RpcAccountInfoConfig {
+    let account_info_config = |min_context_slot| RpcAccountInfoConfig {
         encoding: Some(UiAccountEncoding::Base64),
-        commitment: Some(CommitmentConfig::finalized()),
+        commitment: Some(commitment),
         data_slice: None,
-        min_context_slot: None,
-    };
-    let program_accounts_config = RpcProgramAccountsConfig {
-        filters: None,
-        with_context: Some(true),
-        account_config: account_info_config.clone(),
+        min_context_slot,
     };
+    let mut min_context_slot = None;
+    let mut accounts = vec![];
 
     info!("requesting snapshot");
-    let account_snapshot = rpc_client
-        .get_program_accounts(
-            program_id.to_string(),
-            Some(program_accounts_config.clone()),
-        )
-        .await
-        .map_err_anyhow()?;
+    for (program_id, filters) in programs {
+        let program_accounts_config = RpcProgramAccountsConfig {
+            filters: (!filters.is_empty()).then(|| filters),
+            with_context: Some(true),
+            account_config: account_info_config(min_context_slot),
+        };
+        let account_snapshot = rpc_client
+            .get_program_accounts(program_id.to_string(), Some(program_accounts_config))
+            .await
+            .map_err_anyhow()?;
+        let response = match account_snapshot {
+            OptionalContext::Context(response) => response,
+            OptionalContext::NoContext(_) => anyhow::bail!("bad snapshot format"),
+        };
+        min_context_slot.get_or_insert(response.context.slot);
+        for keyed_account in response.value {
+            let pubkey = Pubkey::from_str(&keyed_account.pubkey)?;
+            let account: Account = keyed_account
+                .account
+                .decode()
+                .ok_or_else(|| anyhow::anyhow!("could not decode account {}", pubkey))?;
+            accounts.push((pubkey, account));
+        }
+    }
+    for chunk in pubkeys.chunks(MULTIPLE_ACCOUNTS_CHUNK_SIZE) {
+        let response = rpc_client
+            .get_multiple_accounts(
+                chunk.iter().map(|pubkey| pubkey.to_string()).collect(),
+                Some(account_info_config(min_context_slot)),
+            )
+            .await
+            .map_err_anyhow()?;
+        min_context_slot.get_or_insert(response.context.slot);
+        // Accounts that don't exist are None
+        for (pubkey, account) in chunk.iter().zip(response.value) {
+            if let Some(account) = account {
+                let account: Account = account
+                    .decode()
+                    .ok_or_else(|| anyhow::anyhow!("could not decode account {}", pubkey))?;
+                accounts.push((*pubkey, account));
+            }
+        }
+    }
+    info!("snapshot received");
+
+    let slot = min_context_slot
+        .ok_or_else(|| anyhow::anyhow!("no programs or accounts to snapshot are configured"))?;
+    let accounts = accounts
+        .into_iter()
+        .map(|(pubkey, account)| AccountWrite::from(pubkey, slot, 0, account))
+        .collect();
+    Ok(SnapshotData { slot, accounts })
+}
+
+/// Collects a snapshot streamed by the geyser plugin
+///
+/// Accounts keep the slot of their latest write. Their write_version is 0,
+/// which is reserved for snapshots.
+async fn get_plugin_snapshot(
+    mut stream: tonic::Streaming<geyser_proto::GetSnapshotResponse>,
+) -> anyhow::Result<SnapshotData> {
+    let mut slot = 0;
+    let mut accounts = vec![];
+    while let Some(response) = stream.next().await {
+        let response = response?;
+        slot = response.slot;
+        for write in response.accounts {
+            accounts.push(snapshot_account_write(write)?);
+        }
+    }
     info!("snapshot received");
-    Ok(account_snapshot)
+    Ok(SnapshotData { slot, accounts })
+}
+
+/// Converts an account write from the plugin for use in a snapshot
+fn snapshot_account_write(mut write: geyser_proto::AccountWrite) -> anyhow::Result<AccountWrite> {
+    decode_account_data(&mut write)?;
+    Ok(AccountWrite {
+        pubkey: Pubkey::new(&write.pubkey),
+        slot: write.slot,
+        write_version: 0,
+        lamports: write.lamports,
+        owner: Pubkey::new(&write.owner),
+        executable: write.executable,
+        rent_epoch: write.rent_epoch,
+        data: write.data,
+        is_selected: write.is_selected,
+        is_closed: write.is_closed,
+        data_slices: data_slices_from_proto(&write.data_slices),
+    })
 }
 
 async fn feed_data_geyser(
     grpc_config: &GrpcSourceConfig,
     tls_config: Option<ClientTlsConfig>,
     snapshot_config: &SnapshotSourceConfig,
-    sender: async_channel::Sender<Message>,
+    resume_slot: &mut Option<u64>,
+    recorder: Option<&Recorder>,
+    resnapshot: &mut watch::Receiver<()>,
+    snapshot_metrics: &mut SnapshotMetrics,
+    sender: async_channel::Sender<SourceMessage>,
 ) -> anyhow::Result<()> {
-    let program_id = Pubkey::from_str(&snapshot_config.program_id)?;
+    // Validate the snapshot config before connecting
+    let snapshot_programs = snapshot_config.programs()?;
+    let snapshot_accounts = snapshot_config.account_pubkeys()?;
 
     let endpoint = Endpoint::from_str(&grpc_config.connection_string)?;
     let channel = if let Some(tls) = tls_config {
@@ -78,12 +181,29 @@ async fn feed_data_geyser(
     }
     .connect()
     .await?;
-    let mut client = AccountsDbClient::new(channel);
+    let access_token = grpc_config
+        .access_token
+        .as_ref()
+        .map(|token| format!("Bearer {}", token).parse::<MetadataValue<Ascii>>())
+        .transpose()?;
+    let mut client =
+        AccountsDbClient::with_interceptor(channel, move |mut request: tonic::Request<()>| {
+            if let Some(access_token) = access_token.clone() {
+                request.metadata_mut().insert("authorization", access_token);
+            }
+            Ok(request)
+        });
 
     let mut update_stream = client
-        .subscribe(geyser_proto::SubscribeRequest {})
+        .subscribe(geyser_proto::SubscribeRequest {
+            start_slot: *resume_slot,
+            include_startup: resume_slot.is_none(),
+            accepted_data_encodings: vec![geyser_proto::account_write::DataEncoding::Zstd as i32],
+            ..Default::default()
+        })
         .await?
-        .into_inner();
+        .into_inner()
+        .flat_map(|update| futures::stream::iter(unbatch(update)));
 
     // We can't get a snapshot immediately since the finalized snapshot would be for a
     // slot in the past and we'd be missing intermediate updates.
@@ -100,6 +220,10 @@ async fn feed_data_geyser(
     // If a snapshot should be performed when ready.
     let mut snapshot_needed = true;
 
+    // If a snapshot was sent or the connection resumed from a previous one. Only then
+    // can a later connection resume from this one.
+    let mut snapshot_done = false;
+
     // The highest "rooted" slot that has been seen.
     let mut max_rooted_slot = 0;
 
@@ -113,10 +237,23 @@ async fn feed_data_geyser(
 
     // Number of slots that we expect "finalized" commitment to lag
     // behind "rooted". This matters for getProgramAccounts based snapshots,
-    // which will have "finalized" commitment.
-    let mut rooted_to_finalized_slots = 30;
+    // which will have "finalized" commitment. Snapshots from the plugin are
+    // for its latest state and snapshot archives for a rooted slot, they need
+    // no such buffer.
+    let initial_rooted_to_finalized_slots =
+        if snapshot_config.from_grpc_plugin || snapshot_config.archive_directory.is_some() {
+            0
+        } else {
+            30
+        };
+    let mut rooted_to_finalized_slots = initial_rooted_to_finalized_slots;
 
     let mut snapshot_future = future::Fuse::terminated();
+    let mut snapshot_started = Instant::now();
+
+    // Latest startup write per pubkey, if the plugin replays the validator startup.
+    // They form a snapshot once EndOfStartup arrives.
+    let mut startup_writes: Option<HashMap<[u8; 32], geyser_proto::AccountWrite>> = None;
 
     // The plugin sends a ping every 5s or so
     let fatal_idle_timeout = Duration::from_secs(60);
@@ -141,6 +278,7 @@ async fn feed_data_geyser(
     let mut slot_pubkey_writes = HashMap::<u64, HashMap<[u8; 32], WriteVersion>>::new();
 
     loop {
+        snapshot_metrics.update_age();
         tokio::select! {
             update = update_stream.next() => {
                 use geyser_proto::{update::UpdateOneof, slot_update::Status};
@@ -148,6 +286,29 @@ async fn feed_data_geyser(
                 match update.update_oneof.as_mut().expect("invalid grpc") {
                     UpdateOneof::SubscribeResponse(subscribe_response) => {
                         first_full_slot = subscribe_response.highest_write_slot + 1;
+                        if subscribe_response.data_encoding.is_none() {
+                            warn!("geyser plugin doesn't negotiate the data encoding, account data is corrupt if it has zstd_compression enabled");
+                        }
+
+                        // If the plugin replays all updates since the last connection, no
+                        // new snapshot is needed
+                        if let (Some(start_slot), Some(oldest_slot)) = (*resume_slot, subscribe_response.oldest_available_slot) {
+                            if start_slot >= oldest_slot {
+                                info!("resuming from slot {}, oldest available slot is {}", start_slot, oldest_slot);
+                                first_full_slot = start_slot;
+                                snapshot_needed = false;
+                                snapshot_done = true;
+                            }
+                        }
+                        if subscribe_response.startup_replay {
+                            info!("plugin replays the validator startup, collecting startup writes as snapshot");
+                            first_full_slot = 0;
+                            snapshot_needed = false;
+                            startup_writes = Some(HashMap::new());
+                        }
+                        if !snapshot_done {
+                            *resume_slot = None;
+                        }
                     },
                     UpdateOneof::SlotUpdate(slot_update) => {
                         let status = slot_update.status;
@@ -157,14 +318,64 @@ async fn feed_data_geyser(
 
                                 // drop data for slots that are well beyond rooted
                                 slot_pubkey_writes.retain(|&k, _| k >= max_rooted_slot - max_out_of_order_slots);
+
+                                // a later connection needs to replay all slots that may still
+                                // receive writes
+                                if snapshot_done {
+                                    *resume_slot = Some(first_full_slot.max(max_rooted_slot - max_out_of_order_slots));
+                                }
                             }
-                            if snapshot_needed && max_rooted_slot - rooted_to_finalized_slots > first_full_slot {
+                            // Only one snapshot is requested at a time
+                            if snapshot_needed && snapshot_future.is_terminated() && max_rooted_slot - rooted_to_finalized_slots > first_full_slot {
                                 snapshot_needed = false;
-                                snapshot_future = tokio::spawn(get_snapshot(snapshot_config.rpc_http_url.clone(), program_id)).fuse();
+                                snapshot_started = Instant::now();
+                                if snapshot_config.from_grpc_plugin {
+                                    info!("requesting snapshot from the geyser plugin");
+                                    let mut client = client.clone();
+                                    let request = geyser_proto::GetSnapshotRequest {
+                                        accounts: snapshot_accounts.iter().map(|pubkey| pubkey.to_string()).collect(),
+                                        owners: snapshot_programs.iter().map(|(program_id, _)| program_id.to_string()).collect(),
+                                        accepted_data_encodings: vec![geyser_proto::account_write::DataEncoding::Zstd as i32],
+                                        // Whatever the plugin's accounts selector keeps
+                                        data_slices: vec![],
+                                    };
+                                    snapshot_future = tokio::spawn(async move {
+                                        get_plugin_snapshot(client.get_snapshot(request).await?.into_inner()).await
+                                    }).fuse();
+                                } else if let Some(archive_directory) = snapshot_config.archive_directory.clone() {
+                                    info!("reading snapshot from the archives in {}", archive_directory);
+                                    let selection = ArchiveSelection {
+                                        programs: snapshot_programs.clone(),
+                                        pubkeys: snapshot_accounts.iter().copied().collect(),
+                                    };
+                                    let min_slot = first_full_slot;
+                                    snapshot_future = tokio::task::spawn_blocking(move || {
+                                        let (slot, paths) = snapshot_archive::newest_archives(Path::new(&archive_directory))?;
+                                        if slot < min_slot {
+                                            // Not worth reading, it gets rejected as too old
+                                            return Ok(SnapshotData { slot, accounts: vec![] });
+                                        }
+                                        let accounts = snapshot_archive::load_snapshot(slot, &paths, &selection)?;
+                                        Ok(SnapshotData { slot, accounts })
+                                    }).fuse();
+                                } else {
+                                    snapshot_future = tokio::spawn(get_snapshot(snapshot_config.clone(), CommitmentConfig::finalized())).fuse();
+                                }
                             }
                         }
                     },
                     UpdateOneof::AccountWrite(write) => {
+                        if let (true, Some(startup_writes)) = (write.is_startup, startup_writes.as_mut()) {
+                            let pubkey_bytes = Pubkey::new(&write.pubkey).to_bytes();
+                            let is_newer = startup_writes.get(&pubkey_bytes).map_or(true, |previous| {
+                                (write.slot, write.write_version) >= (previous.slot, previous.write_version)
+                            });
+                            if is_newer {
+                                startup_writes.insert(pubkey_bytes, write.clone());
+                            }
+                            continue;
+                        }
+
                         if write.slot < first_full_slot {
                             // Don't try to process data for slots where we may have missed writes:
                             // We could not map the write_version correctly for them.
@@ -196,31 +407,92 @@ async fn feed_data_geyser(
                         write.write_version = write_version_mapping.slot as u64;
                         write_version_mapping.slot += 1;
                     },
+                    UpdateOneof::Gap(gap) => {
+                        warn!(
+                            "geyser plugin dropped {} messages for slots {} to {}, resnapshotting",
+                            gap.lost_messages, gap.first_slot, gap.last_slot
+                        );
+
+                        // Writes for the affected slots may be missing and their write_version
+                        // can't be mapped correctly anymore. Skip them and get a new snapshot
+                        // instead of reconnecting.
+                        first_full_slot = first_full_slot.max(gap.last_slot + 1);
+                        slot_pubkey_writes.retain(|&k, _| k < gap.first_slot);
+                        snapshot_needed = true;
+                        snapshot_done = false;
+                        startup_writes = None;
+                        *resume_slot = None;
+                    },
+                    UpdateOneof::EndOfStartup(end_of_startup) => {
+                        if let Some(startup_writes) = startup_writes.take() {
+                            info!("startup snapshot has {} accounts up to slot {}", startup_writes.len(), end_of_startup.slot);
+                            let accounts = startup_writes
+                                .into_values()
+                                .map(snapshot_account_write)
+                                .collect::<anyhow::Result<Vec<_>>>()?;
+                            if let Some(recorder) = recorder {
+                                recorder.record_snapshot(end_of_startup.slot, &accounts);
+                            }
+                            sender
+                                .send(SourceMessage::Snapshot(SnapshotData { slot: end_of_startup.slot, accounts }))
+                                .await
+                                .expect("send success");
+                            snapshot_done = true;
+                        }
+                    },
                     geyser_proto::update::UpdateOneof::Ping(_) => {},
+                    geyser_proto::update::UpdateOneof::Transaction(_) => {},
+                    geyser_proto::update::UpdateOneof::Batch(_) => anyhow::bail!("nested update batch"),
+                }
+                if let Some(recorder) = recorder {
+                    recorder.record(&update);
+                }
+                match update.update_oneof.expect("invalid grpc") {
+                    UpdateOneof::AccountWrite(write) => {
+                        let (pubkey, slot) = (Pubkey::new(&write.pubkey), write.slot);
+                        match account_write_from_proto(write) {
+                            Ok(account_write) => sender.send(SourceMessage::AccountWrite(account_write)).await.expect("send success"),
+                            Err(err) => error!("could not decode data of account {} at slot {}: {:?}", pubkey, slot, err),
+                        }
+                    },
+                    UpdateOneof::SlotUpdate(slot_update) => match slot_update_from_proto(&slot_update) {
+                        Some(slot_update) => sender.send(SourceMessage::SlotUpdate(slot_update)).await.expect("send success"),
+                        None => error!("unexpected slot status: {}", slot_update.status),
+                    },
+                    _ => {},
                 }
-                sender.send(Message::GrpcUpdate(update)).await.expect("send success");
             },
             snapshot = &mut snapshot_future => {
                 let snapshot = snapshot??;
-                if let OptionalContext::Context(snapshot_data) = snapshot {
-                    info!("snapshot is for slot {}, first full slot was {}", snapshot_data.context.slot, first_full_slot);
-                    if snapshot_data.context.slot >= first_full_slot {
-                        sender
-                        .send(Message::Snapshot(snapshot_data))
-                        .await
-                        .expect("send success");
-                    } else {
-                        info!(
-                            "snapshot is too old: has slot {}, expected {} minimum",
-                            snapshot_data.context.slot,
-                            first_full_slot
-                        );
-                        // try again in another 10 slots
-                        snapshot_needed = true;
-                        rooted_to_finalized_slots += 10;
+                info!("snapshot is for slot {}, first full slot was {}", snapshot.slot, first_full_slot);
+                if snapshot.slot >= first_full_slot {
+                    if let Some(recorder) = recorder {
+                        recorder.record_snapshot(snapshot.slot, &snapshot.accounts);
                     }
+                    sender
+                    .send(SourceMessage::Snapshot(snapshot))
+                    .await
+                    .expect("send success");
+                    snapshot_done = true;
+                    snapshot_metrics.on_snapshot(snapshot_started);
+                    // Later snapshots start out with the usual buffer again
+                    rooted_to_finalized_slots = initial_rooted_to_finalized_slots;
                 } else {
-                    anyhow::bail!("bad snapshot format");
+                    info!(
+                        "snapshot is too old: has slot {}, expected {} minimum",
+                        snapshot.slot,
+                        first_full_slot
+                    );
+                    // try again in another 10 slots
+                    snapshot_needed = true;
+                    rooted_to_finalized_slots += 10;
+                }
+            },
+            result = resnapshot.changed() => {
+                // Until the first snapshot is done, one is pending anyway
+                if result.is_ok() && snapshot_done && !snapshot_needed {
+                    info!("resnapshot requested");
+                    snapshot_needed = true;
                 }
             },
             _ = tokio::time::sleep(fatal_idle_timeout) => {
@@ -230,6 +502,80 @@ async fn feed_data_geyser(
     }
 }
 
+/// Splits update batches into their updates
+fn unbatch(
+    update: Result<geyser_proto::Update, tonic::Status>,
+) -> Vec<Result<geyser_proto::Update, tonic::Status>> {
+    use geyser_proto::update::UpdateOneof;
+    match update {
+        Ok(geyser_proto::Update {
+            update_oneof: Some(UpdateOneof::Batch(batch)),
+        }) => batch.updates.into_iter().map(Ok).collect(),
+        update => vec![update],
+    }
+}
+
+/// Decodes account data according to the encoding the plugin used for it
+fn decode_account_data(write: &mut geyser_proto::AccountWrite) -> anyhow::Result<()> {
+    use geyser_proto::account_write::DataEncoding;
+    match DataEncoding::from_i32(write.data_encoding) {
+        Some(DataEncoding::None) => {}
+        Some(DataEncoding::Zstd) => {
+            let mut data = Vec::new();
+            compression::zstd_decompress(&write.data, &mut data)?;
+            write.data = data;
+            write.data_encoding = DataEncoding::None as i32;
+        }
+        None => anyhow::bail!("unknown data encoding {}", write.data_encoding),
+    }
+    Ok(())
+}
+
+/// Converts an account write from the plugin, decoding its data
+pub(crate) fn account_write_from_proto(
+    mut update: geyser_proto::AccountWrite,
+) -> anyhow::Result<AccountWrite> {
+    decode_account_data(&mut update)?;
+    Ok(AccountWrite {
+        pubkey: Pubkey::new(&update.pubkey),
+        slot: update.slot,
+        write_version: update.write_version,
+        lamports: update.lamports,
+        owner: Pubkey::new(&update.owner),
+        executable: update.executable,
+        rent_epoch: update.rent_epoch,
+        data: update.data,
+        is_selected: update.is_selected,
+        is_closed: update.is_closed,
+        data_slices: data_slices_from_proto(&update.data_slices),
+    })
+}
+
+fn data_slices_from_proto(slices: &[geyser_proto::DataSlice]) -> Vec<DataSlice> {
+    slices
+        .iter()
+        .map(|slice| DataSlice {
+            offset: slice.offset,
+            length: slice.length,
+        })
+        .collect()
+}
+
+/// Converts a slot update from the plugin, None if its status is unknown
+pub(crate) fn slot_update_from_proto(update: &geyser_proto::SlotUpdate) -> Option<SlotUpdate> {
+    use geyser_proto::slot_update::Status;
+    let status = Status::from_i32(update.status).map(|v| match v {
+        Status::Processed => SlotStatus::Processed,
+        Status::Confirmed => SlotStatus::Confirmed,
+        Status::Rooted => SlotStatus::Rooted,
+    })?;
+    Some(SlotUpdate {
+        slot: update.slot,
+        parent: update.parent,
+        status,
+    })
+}
+
 fn make_tls_config(config: &TlsConfig) -> ClientTlsConfig {
     let server_root_ca_cert =
         std::fs::read(&config.ca_cert_path).expect("reading server root ca cert");
@@ -243,161 +589,100 @@ fn make_tls_config(config: &TlsConfig) -> ClientTlsConfig {
         .domain_name(&config.domain_name)
 }
 
-pub async fn process_events(
-    config: &SourceConfig,
-    account_write_queue_sender: async_channel::Sender<AccountWrite>,
-    slot_queue_sender: async_channel::Sender<SlotUpdate>,
-    metrics_sender: metrics::Metrics,
-) {
-    // Subscribe to geyser
-    let (msg_sender, msg_receiver) = async_channel::bounded::<Message>(config.dedup_queue_size);
-    for grpc_source in config.grpc_sources.clone() {
-        let msg_sender = msg_sender.clone();
-        let snapshot_source = config.snapshot.clone();
-        let metrics_sender = metrics_sender.clone();
-
-        // Make TLS config if configured
-        let tls_config = grpc_source.tls.as_ref().map(make_tls_config);
-
-        tokio::spawn(async move {
-            let mut metric_retries = metrics_sender.register_u64(format!(
-                "grpc_source_{}_connection_retries",
-                grpc_source.name
-            ));
-            let metric_status =
-                metrics_sender.register_string(format!("grpc_source_{}_status", grpc_source.name));
-
-            // Continuously reconnect on failure
-            loop {
-                metric_status.set("connected".into());
-                let out = feed_data_geyser(
-                    &grpc_source,
-                    tls_config.clone(),
-                    &snapshot_source,
-                    msg_sender.clone(),
-                );
-                let result = out.await;
-                assert!(result.is_err());
-                if let Err(err) = result {
-                    warn!(
-                        "error during communication with the geyser plugin. retrying. {:?}",
-                        err
-                    );
-                }
+/// Streams updates from a geyser plugin, reconnecting on failure
+pub struct GrpcPluginSource {
+    config: GrpcSourceConfig,
+    snapshot_config: SnapshotSourceConfig,
+    tls_config: Option<ClientTlsConfig>,
+    /// Records what the source receives, if configured
+    recorder: Option<Recorder>,
+}
 
-                metric_status.set("disconnected".into());
-                metric_retries.increment();
+impl GrpcPluginSource {
+    pub fn new(
+        config: GrpcSourceConfig,
+        snapshot_config: SnapshotSourceConfig,
+        record_config: Option<&RecordConfig>,
+    ) -> anyhow::Result<Self> {
+        let has_snapshot_accounts = !snapshot_config.programs()?.is_empty()
+            || !snapshot_config.account_pubkeys()?.is_empty();
+        anyhow::ensure!(
+            has_snapshot_accounts,
+            "grpc source {} needs snapshot programs or accounts",
+            config.name
+        );
+        let tls_config = config.tls.as_ref().map(make_tls_config);
+        let recorder = record_config
+            .map(|record_config| Recorder::new(record_config, &config.name))
+            .transpose()?;
+        Ok(Self {
+            config,
+            snapshot_config,
+            tls_config,
+            recorder,
+        })
+    }
+}
 
-                tokio::time::sleep(std::time::Duration::from_secs(
-                    grpc_source.retry_connection_sleep_secs,
-                ))
-                .await;
-            }
-        });
+#[async_trait]
+impl Source for GrpcPluginSource {
+    fn name(&self) -> &str {
+        &self.config.name
     }
 
-    // slot -> (pubkey -> write_version)
-    //
-    // To avoid unnecessarily sending requests to SQL, we track the latest write_version
-    // for each (slot, pubkey). If an already-seen write_version comes in, it can be safely
-    // discarded.
-    let mut latest_write = HashMap::<u64, HashMap<[u8; 32], u64>>::new();
-
-    // Number of slots to retain in latest_write
-    let latest_write_retention = 50;
-
-    let mut metric_account_writes = metrics_sender.register_u64("grpc_account_writes".into());
-    let mut metric_account_queue = metrics_sender.register_u64("account_write_queue".into());
-    let mut metric_slot_queue = metrics_sender.register_u64("slot_update_queue".into());
-    let mut metric_slot_updates = metrics_sender.register_u64("grpc_slot_updates".into());
-    let mut metric_snapshots = metrics_sender.register_u64("grpc_snapshots".into());
-    let mut metric_snapshot_account_writes =
-        metrics_sender.register_u64("grpc_snapshot_account_writes".into());
+    async fn run(&self, context: SourceContext) {
+        let SourceContext {
+            sender,
+            mut resnapshot,
+            metrics: metrics_sender,
+        } = context;
+        let grpc_source = &self.config;
+
+        let mut metric_retries = metrics_sender.register_u64(format!(
+            "grpc_source_{}_connection_retries",
+            grpc_source.name
+        ));
+        let metric_status =
+            metrics_sender.register_string(format!("grpc_source_{}_status", grpc_source.name));
+
+        let mut snapshot_metrics = SnapshotMetrics::new(
+            &metrics_sender,
+            &format!("grpc_source_{}", grpc_source.name),
+        );
+
+        // Slot to resume the stream from after a reconnect, if known
+        let mut resume_slot = None;
+
+        // Continuously reconnect on failure
+        loop {
+            metric_status.set("connected".into());
+            let out = feed_data_geyser(
+                grpc_source,
+                self.tls_config.clone(),
+                &self.snapshot_config,
+                &mut resume_slot,
+                self.recorder.as_ref(),
+                &mut resnapshot,
+                &mut snapshot_metrics,
+                sender.clone(),
+            );
+            let result = out.await;
+            assert!(result.is_err());
+            if let Err(err) = result {
+                warn!(
+                    "error during communication with the geyser plugin. retrying. {:?}",
+                    err
+                );
+            }
 
-    loop {
-        let msg = msg_receiver.recv().await.expect("sender must not close");
+            metric_status.set("disconnected".into());
+            metric_retries.increment();
 
-        match msg {
-            Message::GrpcUpdate(update) => {
-                match update.update_oneof.expect("invalid grpc") {
-                    geyser_proto::update::UpdateOneof::AccountWrite(update) => {
-                        assert!(update.pubkey.len() == 32);
-                        assert!(update.owner.len() == 32);
-
-                        metric_account_writes.increment();
-                        metric_account_queue.set(account_write_queue_sender.len() as u64);
-
-                        // Skip writes that a different server has already sent
-                        let pubkey_writes = latest_write.entry(update.slot).or_default();
-                        let pubkey_bytes = Pubkey::new(&update.pubkey).to_bytes();
-                        let writes = pubkey_writes.entry(pubkey_bytes).or_insert(0);
-                        if update.write_version <= *writes {
-                            continue;
-                        }
-                        *writes = update.write_version;
-                        latest_write.retain(|&k, _| k >= update.slot - latest_write_retention);
-
-                        account_write_queue_sender
-                            .send(AccountWrite {
-                                pubkey: Pubkey::new(&update.pubkey),
-                                slot: update.slot,
-                                write_version: update.write_version,
-                                lamports: update.lamports,
-                                owner: Pubkey::new(&update.owner),
-                                executable: update.executable,
-                                rent_epoch: update.rent_epoch,
-                                data: update.data,
-                                is_selected: update.is_selected,
-                            })
-                            .await
-                            .expect("send success");
-                    }
-                    geyser_proto::update::UpdateOneof::SlotUpdate(update) => {
-                        metric_slot_updates.increment();
-                        metric_slot_queue.set(slot_queue_sender.len() as u64);
-
-                        use geyser_proto::slot_update::Status;
-                        let status = Status::from_i32(update.status).map(|v| match v {
-                            Status::Processed => SlotStatus::Processed,
-                            Status::Confirmed => SlotStatus::Confirmed,
-                            Status::Rooted => SlotStatus::Rooted,
-                        });
-                        if status.is_none() {
-                            error!("unexpected slot status: {}", update.status);
-                            continue;
-                        }
-                        let slot_update = SlotUpdate {
-                            slot: update.slot,
-                            parent: update.parent,
-                            status: status.expect("qed"),
-                        };
-
-                        slot_queue_sender
-                            .send(slot_update)
-                            .await
-                            .expect("send success");
-                    }
-                    geyser_proto::update::UpdateOneof::Ping(_) => {}
-                    geyser_proto::update::UpdateOneof::SubscribeResponse(_) => {}
-                }
-            }
-            Message::Snapshot(update) => {
-                metric_snapshots.increment();
-                info!("processing snapshot...");
-                for keyed_account in update.value {
-                    metric_snapshot_account_writes.increment();
-                    metric_account_queue.set(account_write_queue_sender.len() as u64);
-
-                    // TODO: Resnapshot on invalid data?
-                    let account: Account = keyed_account.account.decode().unwrap();
-                    let pubkey = Pubkey::from_str(&keyed_account.pubkey).unwrap();
-                    account_write_queue_sender
-                        .send(AccountWrite::from(pubkey, update.context.slot, 0, account))
-                        .await
-                        .expect("send success");
-                }
-                info!("processing snapshot done");
-            }
+            snapshot_metrics
+                .sleep(std::time::Duration::from_secs(
+                    grpc_source.retry_connection_sleep_secs,
+                ))
+                .await;
         }
     }
 }
@@ -1,9 +1,14 @@
 pub mod chain_data;
+pub mod compression;
+pub mod file_replay_source;
 pub mod grpc_plugin_source;
 pub mod memory_target;
 pub mod metrics;
 pub mod postgres_target;
 pub mod postgres_types_numeric;
+pub mod recorder;
+pub mod snapshot_archive;
+pub mod source;
 pub mod websocket_source;
 
 pub use chain_data::SlotStatus;
@@ -11,8 +16,9 @@ pub use chain_data::SlotStatus;
 use {
     async_trait::async_trait,
     serde_derive::Deserialize,
-    solana_sdk::{account::Account, pubkey::Pubkey},
-    std::sync::Arc,
+    solana_client::rpc_filter::RpcFilterType,
+    solana_sdk::{account::Account, commitment_config::CommitmentLevel, pubkey::Pubkey},
+    std::{str::FromStr, sync::Arc},
 };
 
 trait AnyhowWrap {
@@ -38,6 +44,17 @@ pub struct AccountWrite {
     pub rent_epoch: u64,
     pub data: Vec<u8>,
     pub is_selected: bool,
+    /// The write closed the account, consumers should treat it as deleted
+    pub is_closed: bool,
+    /// If not empty, `data` only holds these parts of the account data, concatenated
+    pub data_slices: Vec<DataSlice>,
+}
+
+/// A part of the account data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DataSlice {
+    pub offset: u64,
+    pub length: u64,
 }
 
 impl AccountWrite {
@@ -46,12 +63,14 @@ impl AccountWrite {
             pubkey,
             slot,
             write_version,
+            is_closed: account.lamports == 0 && account.owner == solana_sdk::system_program::id(),
             lamports: account.lamports,
             owner: account.owner,
             executable: account.executable,
             rent_epoch: account.rent_epoch,
             data: account.data,
             is_selected: true,
+            data_slices: vec![],
         }
     }
 }
@@ -106,20 +125,186 @@ pub struct GrpcSourceConfig {
     pub connection_string: String,
     pub retry_connection_sleep_secs: u64,
     pub tls: Option<TlsConfig>,
+    /// Sent to the plugin as `authorization: Bearer <token>` metadata
+    pub access_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebsocketSourceConfig {
+    pub name: String,
+    pub rpc_ws_url: String,
+    /// Commitment of the subscriptions and snapshots, like "confirmed"
+    #[serde(default = "default_websocket_commitment")]
+    pub commitment: CommitmentLevel,
+    /// Seconds to sleep before the first reconnection attempt, doubling with
+    /// each failed attempt
+    #[serde(default = "default_retry_connection_sleep_secs")]
+    pub retry_connection_sleep_secs: u64,
+    #[serde(default = "default_max_retry_connection_sleep_secs")]
+    pub max_retry_connection_sleep_secs: u64,
+}
+
+fn default_websocket_commitment() -> CommitmentLevel {
+    CommitmentLevel::Processed
+}
+
+fn default_retry_connection_sleep_secs() -> u64 {
+    1
+}
+
+fn default_max_retry_connection_sleep_secs() -> u64 {
+    60
+}
+
+/// A source of updates, selected by its `type`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum SourceKindConfig {
+    Grpc(GrpcSourceConfig),
+    Websocket(WebsocketSourceConfig),
+    FileReplay(FileReplaySourceConfig),
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct SourceConfig {
     pub dedup_queue_size: usize,
+    /// Same as `sources` entries of type "grpc"
+    #[serde(default)]
     pub grpc_sources: Vec<GrpcSourceConfig>,
+    /// Sources of any type, they all run at once
+    #[serde(default)]
+    pub sources: Vec<SourceKindConfig>,
     pub snapshot: SnapshotSourceConfig,
-    pub rpc_ws_url: String,
+    /// Record the updates and snapshots of the grpc sources
+    #[serde(default)]
+    pub record: Option<RecordConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RecordConfig {
+    /// Each grpc source records to a subdirectory named after it
+    pub directory: String,
+    /// Number of slots covered by each segment file
+    #[serde(default = "default_segment_slots")]
+    pub segment_slots: u64,
+    /// Segments are finished after this many seconds even if they don't
+    /// cover all their slots yet, unfinished segments aren't replayed
+    #[serde(default = "default_segment_max_secs")]
+    pub segment_max_secs: u64,
+}
+
+fn default_segment_slots() -> u64 {
+    1000
+}
+
+fn default_segment_max_secs() -> u64 {
+    60
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SnapshotProgramConfig {
+    pub program_id: String,
+    /// getProgramAccounts filters, like `{ dataSize = 165 }`. Not supported
+    /// together with `from_grpc_plugin`.
+    #[serde(default)]
+    pub filters: Vec<RpcFilterType>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct SnapshotSourceConfig {
     pub rpc_http_url: String,
+    /// A program to snapshot all accounts of, in addition to `programs`
+    #[serde(default)]
     pub program_id: String,
+    #[serde(default)]
+    pub programs: Vec<SnapshotProgramConfig>,
+    /// Individual accounts to snapshot, fetched with getMultipleAccounts
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    /// Request snapshots from the geyser plugin instead of from rpc_http_url.
+    /// The plugin can't apply the program filters.
+    #[serde(default)]
+    pub from_grpc_plugin: bool,
+    /// Read snapshots from the newest `snapshot-*.tar.zst` archive in this
+    /// directory, and the newest incremental one based on it, instead of from
+    /// rpc_http_url
+    #[serde(default)]
+    pub archive_directory: Option<String>,
+    /// Take a new snapshot this often while connected (0 to disable, which
+    /// websocket sources refuse). Sending SIGUSR1 to the process also triggers
+    /// a new snapshot.
+    #[serde(default)]
+    pub resnapshot_interval_secs: u64,
+}
+
+impl SnapshotSourceConfig {
+    /// All programs to snapshot, along with their filters
+    pub fn programs(&self) -> anyhow::Result<Vec<(Pubkey, Vec<RpcFilterType>)>> {
+        let mut programs = vec![];
+        if !self.program_id.is_empty() {
+            programs.push((Pubkey::from_str(&self.program_id)?, vec![]));
+        }
+        for program in &self.programs {
+            programs.push((
+                Pubkey::from_str(&program.program_id)?,
+                program.filters.clone(),
+            ));
+        }
+        Ok(programs)
+    }
+
+    pub fn account_pubkeys(&self) -> anyhow::Result<Vec<Pubkey>> {
+        self.accounts
+            .iter()
+            .map(|account| Ok(Pubkey::from_str(account)?))
+            .collect()
+    }
+
+    /// Returns an error for settings that can't be used together
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let has_filters = self
+            .programs
+            .iter()
+            .any(|program| !program.filters.is_empty());
+        anyhow::ensure!(
+            !(self.from_grpc_plugin && has_filters),
+            "snapshot program filters can't be used with from_grpc_plugin"
+        );
+        Ok(())
+    }
+}
+
+/// How fast captured updates are replayed
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "mode")]
+pub enum ReplayPacing {
+    /// One slot every 400ms, like on the cluster
+    Realtime,
+    /// Real time sped up by this factor
+    Accelerated { speed: f64 },
+    /// As fast as the targets accept updates
+    Unpaced,
+}
+
+impl Default for ReplayPacing {
+    fn default() -> Self {
+        ReplayPacing::Unpaced
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FileReplaySourceConfig {
+    pub name: String,
+    /// Capture files written by the geyser plugin, or directories of them
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub pacing: ReplayPacing,
+    /// Only replay updates for slots >= start_slot
+    #[serde(default)]
+    pub start_slot: Option<u64>,
+    /// Only replay updates for slots <= end_slot
+    #[serde(default)]
+    pub end_slot: Option<u64>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -163,27 +348,89 @@ impl AccountTable for RawAccountTable {
         let write_version = account_write.write_version as i64;
         let lamports = account_write.lamports as i64;
         let rent_epoch = account_write.rent_epoch as i64;
+        let data_slices = (!account_write.data_slices.is_empty()).then(|| {
+            account_write
+                .data_slices
+                .iter()
+                .flat_map(|slice| [slice.offset as i64, slice.length as i64])
+                .collect::<Vec<i64>>()
+        });
 
-        // TODO: should update for same write_version to work with websocket input
         let query = postgres_query::query!(
             "INSERT INTO account_write
-            (pubkey_id, slot, write_version, is_selected,
-             owner_id, lamports, executable, rent_epoch, data)
+            (pubkey_id, slot, write_version, is_selected, is_closed,
+             owner_id, lamports, executable, rent_epoch, data, data_slices)
             VALUES
-            (map_pubkey($pubkey), $slot, $write_version, $is_selected,
-             map_pubkey($owner), $lamports, $executable, $rent_epoch, $data)
+            (map_pubkey($pubkey), $slot, $write_version, $is_selected, $is_closed,
+             map_pubkey($owner), $lamports, $executable, $rent_epoch, $data, $data_slices)
             ON CONFLICT (pubkey_id, slot, write_version) DO NOTHING",
             pubkey,
             slot,
             write_version,
             is_selected = account_write.is_selected,
+            is_closed = account_write.is_closed,
             owner,
             lamports,
             executable = account_write.executable,
             rent_epoch,
             data = account_write.data,
+            data_slices,
         );
         let _ = query.execute(client).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn snapshot_config(config: serde_json::Value) -> SnapshotSourceConfig {
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_programs() {
+        let program_id = Pubkey::new_unique();
+        let token_program_id = Pubkey::new_unique();
+        let oracle = Pubkey::new_unique();
+        let config = snapshot_config(serde_json::json!({
+            "rpc_http_url": "http://localhost:8899",
+            "program_id": program_id.to_string(),
+            "programs": [{
+                "program_id": token_program_id.to_string(),
+                "filters": [{"dataSize": 165}],
+            }],
+            "accounts": [oracle.to_string()],
+        }));
+        assert_eq!(
+            config.programs().unwrap(),
+            vec![
+                (program_id, vec![]),
+                (token_program_id, vec![RpcFilterType::DataSize(165)]),
+            ]
+        );
+        assert_eq!(config.account_pubkeys().unwrap(), vec![oracle]);
+        assert!(config.validate().is_ok());
+
+        // filters can't be applied to snapshots from the geyser plugin
+        let mut plugin_config = config.clone();
+        plugin_config.from_grpc_plugin = true;
+        assert!(plugin_config.validate().is_err());
+        plugin_config.programs[0].filters.clear();
+        assert!(plugin_config.validate().is_ok());
+
+        // everything is optional except the rpc url
+        let config = snapshot_config(serde_json::json!({
+            "rpc_http_url": "http://localhost:8899",
+        }));
+        assert!(config.programs().unwrap().is_empty());
+        assert!(config.account_pubkeys().unwrap().is_empty());
+
+        let config = snapshot_config(serde_json::json!({
+            "rpc_http_url": "http://localhost:8899",
+            "accounts": ["not a pubkey"],
+        }));
+        assert!(config.account_pubkeys().is_err());
+    }
+}
@@ -21,12 +21,15 @@ pub async fn init(
         loop {
             tokio::select! {
                 Ok(account_write) = account_write_queue_receiver.recv() => {
+                    // Sliced account data is kept as is, the consumer that
+                    // configured the slices knows its layout
                     let mut chain = chain_data.write().unwrap();
                     chain.update_account(
                         account_write.pubkey,
                         AccountData {
                             slot: account_write.slot,
                             write_version: account_write.write_version,
+                            is_closed: account_write.is_closed,
                             account: WritableAccount::create(
                                 account_write.lamports,
                                 account_write.data.clone(),
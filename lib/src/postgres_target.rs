@@ -187,69 +187,97 @@ impl Slots {
     }
 }
 
-fn make_cleanup_steps(tables: &[String]) -> HashMap<String, String> {
-    let mut steps = HashMap::<String, String>::new();
-
-    // Delete all account writes that came before the newest rooted slot except
-    // for the newest rooted write for each pubkey.
-    // This could be older rooted writes or writes in uncled slots that came
-    // before the newest rooted slot.
-    //
-    // Also delete _all_ writes from before the newest snapshot, because these may
-    // be for deleted accounts where the deletion event was missed. Snapshots
-    // provide a new state for all live accounts, but don't tell us about deleted
-    // accounts.
-    //
-    // The way this is done, by taking the newest snapshot that's at least
-    // min_snapshot_age behind the newest rooted slot is a workaround: we don't know
-    // how long it'll take to insert snapshot data, but assume it'll be done by that
-    // time.
-    let min_snapshot_age = 300;
-    steps.extend(
-        tables
-            .iter()
-            .map(|table_name| {
-                let sql = format!(
-                    "WITH
-                    newest_rooted AS (
-                        SELECT max(slot) AS newest_rooted_slot FROM slot WHERE status = 'Rooted'),
-                    newest_snapshot AS (
-                        SELECT max(slot) AS newest_snapshot_slot FROM account_write, newest_rooted
-                        WHERE write_version = 0 AND slot + {min_snapshot_age} < newest_rooted_slot)
-                DELETE FROM {table} AS data
-                USING
-                    newest_rooted,
-                    newest_snapshot,
-                    (SELECT DISTINCT ON(pubkey_id) pubkey_id, slot, write_version
-                     FROM {table}
-                     LEFT JOIN slot USING(slot)
-                     CROSS JOIN newest_rooted
-                     WHERE slot <= newest_rooted_slot AND (status = 'Rooted' OR status is NULL)
-                     ORDER BY pubkey_id, slot DESC, write_version DESC
-                     ) newest_rooted_write
-                WHERE
-                    data.pubkey_id = newest_rooted_write.pubkey_id AND (
-                        data.slot < newest_snapshot_slot OR (
-                            data.slot <= newest_rooted_slot
-                            AND (data.slot != newest_rooted_write.slot OR data.write_version != newest_rooted_write.write_version)
-                        )
-                    )",
-                    table = table_name,
-                    min_snapshot_age = min_snapshot_age,
-                );
-                (format!("delete old writes in {}", table_name), sql)
-            })
-            .collect::<HashMap<String, String>>(),
-    );
+fn make_cleanup_steps(tables: &[String]) -> Vec<(String, String)> {
+    let mut steps = vec![];
+
+    // The closed accounts of all tables are found through account_write, so its
+    // writes are deleted last
+    let ordered_tables = tables
+        .iter()
+        .filter(|table_name| *table_name != "account_write")
+        .chain(
+            tables
+                .iter()
+                .filter(|table_name| *table_name == "account_write"),
+        );
+    for table_name in ordered_tables {
+        // Delete all account writes that came before the newest rooted slot except
+        // for the newest rooted write for each pubkey.
+        // This could be older rooted writes or writes in uncled slots that came
+        // before the newest rooted slot.
+        //
+        // Also delete _all_ writes from before the newest snapshot, because these may
+        // be for deleted accounts where the deletion event was missed. Snapshots
+        // provide a new state for all live accounts, but don't tell us about deleted
+        // accounts.
+        //
+        // The way this is done, by taking the newest snapshot that's at least
+        // min_snapshot_age behind the newest rooted slot is a workaround: we don't know
+        // how long it'll take to insert snapshot data, but assume it'll be done by that
+        // time.
+        let min_snapshot_age = 300;
+        let sql = format!(
+            "WITH
+            newest_rooted AS (
+                SELECT max(slot) AS newest_rooted_slot FROM slot WHERE status = 'Rooted'),
+            newest_snapshot AS (
+                SELECT max(slot) AS newest_snapshot_slot FROM account_write, newest_rooted
+                WHERE write_version = 0 AND slot + {min_snapshot_age} < newest_rooted_slot)
+        DELETE FROM {table} AS data
+        USING
+            newest_rooted,
+            newest_snapshot,
+            (SELECT DISTINCT ON(pubkey_id) pubkey_id, slot, write_version
+             FROM {table}
+             LEFT JOIN slot USING(slot)
+             CROSS JOIN newest_rooted
+             WHERE slot <= newest_rooted_slot AND (status = 'Rooted' OR status is NULL)
+             ORDER BY pubkey_id, slot DESC, write_version DESC
+             ) newest_rooted_write
+        WHERE
+            data.pubkey_id = newest_rooted_write.pubkey_id AND (
+                data.slot < newest_snapshot_slot OR (
+                    data.slot <= newest_rooted_slot
+                    AND (data.slot != newest_rooted_write.slot OR data.write_version != newest_rooted_write.write_version)
+                )
+            )",
+            table = table_name,
+            min_snapshot_age = min_snapshot_age,
+        );
+        steps.push((format!("delete old writes in {}", table_name), sql));
+
+        // Delete all writes to accounts that were closed by their newest rooted write,
+        // including the closing write itself. The views already hide such accounts.
+        let sql = format!(
+            "WITH
+            newest_rooted AS (
+                SELECT max(slot) AS newest_rooted_slot FROM slot WHERE status = 'Rooted')
+        DELETE FROM {table} AS data
+        USING
+            (SELECT DISTINCT ON(pubkey_id) pubkey_id, slot, is_closed
+             FROM account_write
+             LEFT JOIN slot USING(slot)
+             CROSS JOIN newest_rooted
+             WHERE slot <= newest_rooted_slot AND (status = 'Rooted' OR status is NULL)
+             ORDER BY pubkey_id, slot DESC, write_version DESC
+             ) newest_rooted_write
+        WHERE
+            data.pubkey_id = newest_rooted_write.pubkey_id
+            AND newest_rooted_write.is_closed
+            AND data.slot <= newest_rooted_write.slot",
+            table = table_name,
+        );
+        steps.push((format!("delete closed accounts in {}", table_name), sql));
+    }
 
     // Delete information about older slots
-    steps.insert(
+    steps.push((
         "delete old slots".into(),
         "DELETE FROM slot
          USING (SELECT max(slot) as newest_rooted_slot FROM slot WHERE status = 'Rooted') s
          WHERE slot + 1000 < newest_rooted_slot"
             .into(),
-    );
+    ));
 
     steps
 }
@@ -619,3 +647,30 @@ pub async fn init(
 
     Ok((account_write_queue_sender, slot_queue_sender))
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cleanup_steps_order() {
+        let tables = vec![
+            "account_write".to_string(),
+            "mango_account_write".to_string(),
+        ];
+        let names = make_cleanup_steps(&tables)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "delete old writes in mango_account_write",
+                "delete closed accounts in mango_account_write",
+                "delete old writes in account_write",
+                "delete closed accounts in account_write",
+                "delete old slots",
+            ]
+        );
+    }
+}
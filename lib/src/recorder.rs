@@ -0,0 +1,353 @@
+use prost::Message;
+
+use log::*;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{grpc_plugin_source::geyser_proto, AccountWrite, RecordConfig};
+
+/// Suffix of segment files that are still being written, skipped on replay
+pub(crate) const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Number of updates that may wait for the recorder thread before updates get dropped
+const QUEUE_SIZE: usize = 100_000;
+
+/// First and last slot of a segment file named by the recorder
+///
+/// Segment files are named `slots-<first slot>-<last slot>-<unix millis>.pb.zst`
+/// and only contain updates for slots <= last slot.
+pub(crate) fn segment_slots(path: &Path) -> Option<(u64, u64)> {
+    let name = path.file_name()?.to_str()?;
+    let mut parts = name.strip_prefix("slots-")?.split('-');
+    let first_slot = parts.next()?.parse().ok()?;
+    let last_slot = parts.next()?.parse().ok()?;
+    Some((first_slot, last_slot))
+}
+
+struct Segment {
+    encoder: zstd::stream::write::Encoder<'static, BufWriter<File>>,
+    path: PathBuf,
+    last_slot: u64,
+    opened: Instant,
+}
+
+/// Writes updates as length-delimited protobuf to zstd compressed segment files
+///
+/// Each segment covers `segment_slots` slots: an update for a later slot
+/// starts a new segment. Updates without a slot go to the current segment.
+/// Segments are also finished once they are open for `max_segment_duration`,
+/// so at most that much is lost if the process gets killed.
+struct SegmentWriter {
+    directory: PathBuf,
+    segment_slots: u64,
+    max_segment_duration: Duration,
+    segment: Option<Segment>,
+}
+
+impl SegmentWriter {
+    /// Time until the open segment is due to be finished
+    fn time_to_finish(&self) -> Option<Duration> {
+        self.segment.as_ref().map(|segment| {
+            self.max_segment_duration
+                .saturating_sub(segment.opened.elapsed())
+        })
+    }
+
+    /// Finishes the open segment if it is open for too long
+    fn finish_if_due(&mut self) -> anyhow::Result<()> {
+        if self.time_to_finish() == Some(Duration::ZERO) {
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, slot: Option<u64>, update: &geyser_proto::Update) -> anyhow::Result<()> {
+        if let (Some(slot), Some(segment)) = (slot, self.segment.as_ref()) {
+            if slot > segment.last_slot {
+                self.finish()?;
+            }
+        }
+        if self.segment.is_none() {
+            match slot {
+                Some(slot) => self.segment = Some(self.open(slot)?),
+                // Updates without a slot before the first segment are dropped
+                None => return Ok(()),
+            }
+        }
+        let segment = self.segment.as_mut().expect("segment was opened");
+        segment
+            .encoder
+            .write_all(&update.encode_length_delimited_to_vec())?;
+        Ok(())
+    }
+
+    fn open(&self, slot: u64) -> anyhow::Result<Segment> {
+        let first_slot = slot - slot % self.segment_slots;
+        let last_slot = first_slot + self.segment_slots - 1;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self.directory.join(format!(
+            "slots-{:012}-{:012}-{}.pb.zst",
+            first_slot, last_slot, millis
+        ));
+        let mut partial_path = path.clone().into_os_string();
+        partial_path.push(PARTIAL_SUFFIX);
+        let writer = BufWriter::new(File::create(partial_path)?);
+        Ok(Segment {
+            encoder: zstd::stream::write::Encoder::new(writer, 0)?,
+            path,
+            last_slot,
+            opened: Instant::now(),
+        })
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(segment) = self.segment.take() {
+            segment.encoder.finish()?.flush()?;
+            let mut partial_path = segment.path.clone().into_os_string();
+            partial_path.push(PARTIAL_SUFFIX);
+            std::fs::rename(partial_path, &segment.path)?;
+            debug!("finished segment {:?}", segment.path);
+        }
+        Ok(())
+    }
+}
+
+/// Records the updates a grpc source received, for replaying them with the
+/// file replay source
+///
+/// Writing happens on a separate thread. If it falls behind, updates are
+/// dropped rather than holding up the source.
+#[derive(Clone)]
+pub struct Recorder {
+    sender: mpsc::SyncSender<(Option<u64>, geyser_proto::Update)>,
+}
+
+impl Recorder {
+    /// Starts recording into a subdirectory of the configured directory
+    pub fn new(config: &RecordConfig, name: &str) -> anyhow::Result<Self> {
+        anyhow::ensure!(config.segment_slots > 0, "segment_slots must be positive");
+        anyhow::ensure!(
+            config.segment_max_secs > 0,
+            "segment_max_secs must be positive"
+        );
+        let directory = Path::new(&config.directory).join(name);
+        std::fs::create_dir_all(&directory)?;
+        info!("recording source {} to {:?}", name, directory);
+
+        let mut writer = SegmentWriter {
+            directory,
+            segment_slots: config.segment_slots,
+            max_segment_duration: Duration::from_secs(config.segment_max_secs),
+            segment: None,
+        };
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name(format!("recorder-{}", name))
+            .spawn(move || loop {
+                let received = match writer.time_to_finish() {
+                    Some(timeout) => receiver.recv_timeout(timeout),
+                    None => receiver
+                        .recv()
+                        .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+                let result = match received {
+                    Ok((slot, update)) => writer
+                        .write(slot, &update)
+                        .and_then(|()| writer.finish_if_due()),
+                    Err(mpsc::RecvTimeoutError::Timeout) => writer.finish(),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        if let Err(err) = writer.finish() {
+                            error!("finishing the recorded segment failed: {:?}", err);
+                        }
+                        return;
+                    }
+                };
+                if let Err(err) = result {
+                    error!("recording failed, stopping: {:?}", err);
+                    return;
+                }
+            })?;
+        Ok(Self { sender })
+    }
+
+    /// Records an update received from the geyser plugin
+    pub fn record(&self, update: &geyser_proto::Update) {
+        use geyser_proto::update::UpdateOneof;
+        let slot = match update.update_oneof.as_ref() {
+            Some(UpdateOneof::AccountWrite(write)) => Some(write.slot),
+            Some(UpdateOneof::SlotUpdate(slot_update)) => Some(slot_update.slot),
+            Some(UpdateOneof::Transaction(transaction)) => Some(transaction.slot),
+            _ => None,
+        };
+        self.send(slot, update.clone());
+    }
+
+    /// Records a snapshot as a batch of account writes with write_version 0
+    pub fn record_snapshot(&self, slot: u64, accounts: &[AccountWrite]) {
+        use geyser_proto::update::UpdateOneof;
+        let updates = accounts
+            .iter()
+            .map(|write| geyser_proto::Update {
+                update_oneof: Some(UpdateOneof::AccountWrite(geyser_proto::AccountWrite {
+                    slot: write.slot,
+                    pubkey: write.pubkey.to_bytes().to_vec(),
+                    lamports: write.lamports,
+                    owner: write.owner.to_bytes().to_vec(),
+                    executable: write.executable,
+                    rent_epoch: write.rent_epoch,
+                    data: write.data.clone(),
+                    write_version: 0,
+                    is_selected: write.is_selected,
+                    is_closed: write.is_closed,
+                    data_slices: write
+                        .data_slices
+                        .iter()
+                        .map(|slice| geyser_proto::DataSlice {
+                            offset: slice.offset,
+                            length: slice.length,
+                        })
+                        .collect(),
+                    ..Default::default()
+                })),
+            })
+            .collect();
+        self.send(
+            Some(slot),
+            geyser_proto::Update {
+                update_oneof: Some(UpdateOneof::Batch(geyser_proto::UpdateBatch { updates })),
+            },
+        );
+    }
+
+    fn send(&self, slot: Option<u64>, update: geyser_proto::Update) {
+        match self.sender.try_send((slot, update)) {
+            Ok(()) => {}
+            Err(mpsc::TrySendError::Full(_)) => warn!("recorder is too slow, dropping an update"),
+            // The recorder thread already logged why it stopped
+            Err(mpsc::TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use geyser_proto::update::UpdateOneof;
+
+    fn slot_update(slot: u64) -> geyser_proto::Update {
+        geyser_proto::Update {
+            update_oneof: Some(UpdateOneof::SlotUpdate(geyser_proto::SlotUpdate {
+                slot,
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let directory =
+            std::env::temp_dir().join(format!("recorder-test-{}-{}", std::process::id(), nanos));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn segment_files(directory: &Path) -> Vec<PathBuf> {
+        let mut files = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    fn read_slots(path: &Path) -> Vec<u64> {
+        let data = zstd::stream::decode_all(File::open(path).unwrap()).unwrap();
+        let mut buf = data.as_slice();
+        let mut slots = vec![];
+        while !buf.is_empty() {
+            match geyser_proto::Update::decode_length_delimited(&mut buf)
+                .unwrap()
+                .update_oneof
+            {
+                Some(UpdateOneof::SlotUpdate(slot_update)) => slots.push(slot_update.slot),
+                _ => panic!("unexpected update"),
+            }
+        }
+        slots
+    }
+
+    #[test]
+    fn test_segment_slots() {
+        assert_eq!(
+            segment_slots(Path::new(
+                "/captures/slots-000000001000-000000001999-1650000000000.pb.zst"
+            )),
+            Some((1000, 1999))
+        );
+        assert_eq!(
+            segment_slots(Path::new("slots-5-9-1.pb.zst.partial")),
+            Some((5, 9))
+        );
+        assert_eq!(segment_slots(Path::new("updates-000001.bin")), None);
+        assert_eq!(segment_slots(Path::new("slots-x-9-1.pb.zst")), None);
+        assert_eq!(segment_slots(Path::new("slots-5")), None);
+    }
+
+    #[test]
+    fn test_segment_writer() {
+        let directory = temp_dir();
+        let mut writer = SegmentWriter {
+            directory: directory.clone(),
+            segment_slots: 10,
+            max_segment_duration: Duration::from_secs(3600),
+            segment: None,
+        };
+
+        // updates without a slot before the first segment are dropped
+        writer.write(None, &slot_update(1)).unwrap();
+        assert!(writer.segment.is_none());
+        assert_eq!(writer.time_to_finish(), None);
+
+        writer.write(Some(12), &slot_update(12)).unwrap();
+        writer.write(None, &slot_update(13)).unwrap();
+        // earlier slots still go to the open segment
+        writer.write(Some(5), &slot_update(5)).unwrap();
+        let files = segment_files(&directory);
+        assert_eq!(files.len(), 1);
+        assert!(files[0].to_string_lossy().ends_with(PARTIAL_SUFFIX));
+
+        writer.write(Some(25), &slot_update(25)).unwrap();
+        writer.finish().unwrap();
+        let files = segment_files(&directory);
+        assert_eq!(files.len(), 2);
+        assert_eq!(segment_slots(&files[0]), Some((10, 19)));
+        assert_eq!(segment_slots(&files[1]), Some((20, 29)));
+        assert_eq!(read_slots(&files[0]), vec![12, 13, 5]);
+        assert_eq!(read_slots(&files[1]), vec![25]);
+
+        // segments open for too long get finished, the sleep keeps file names unique
+        std::thread::sleep(Duration::from_millis(2));
+        writer.max_segment_duration = Duration::ZERO;
+        writer.write(Some(26), &slot_update(26)).unwrap();
+        writer.finish_if_due().unwrap();
+        assert!(writer.segment.is_none());
+        let files = segment_files(&directory);
+        assert_eq!(files.len(), 3);
+        assert!(files
+            .iter()
+            .all(|file| !file.to_string_lossy().ends_with(PARTIAL_SUFFIX)));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
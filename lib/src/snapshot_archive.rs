@@ -0,0 +1,466 @@
+use solana_client::rpc_filter::RpcFilterType;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+
+use log::*;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::AccountWrite;
+
+// Layout of an account stored in an append-vec: StoredMeta (write_version,
+// data_len, pubkey), AccountMeta (lamports, rent_epoch, owner, executable and
+// padding), the account hash and then the data, padded to 8 bytes.
+const STORED_META_SIZE: usize = 48;
+const ACCOUNT_META_SIZE: usize = 56;
+const HASH_SIZE: usize = 32;
+const HEADER_SIZE: usize = STORED_META_SIZE + ACCOUNT_META_SIZE + HASH_SIZE;
+
+/// Larger data lengths mean the rest of the append-vec isn't valid
+const MAX_PERMITTED_DATA_LENGTH: u64 = 10 * 1024 * 1024;
+
+/// Layout of spl token accounts, extended token-2022 accounts store their
+/// account type right after the base account
+const SPL_TOKEN_ACCOUNT_LEN: usize = 165;
+const SPL_TOKEN_MULTISIG_LEN: usize = 355;
+const SPL_TOKEN_ACCOUNT_STATE_OFFSET: usize = 108;
+const SPL_TOKEN_ACCOUNT_TYPE_ACCOUNT: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+enum ArchiveName {
+    Full { slot: u64 },
+    Incremental { base_slot: u64, slot: u64 },
+}
+
+/// Parses `snapshot-<slot>-<hash>.tar.zst` and
+/// `incremental-snapshot-<base slot>-<slot>-<hash>.tar.zst`
+fn parse_archive_name(name: &str) -> Option<ArchiveName> {
+    let name = name.strip_suffix(".tar.zst")?;
+    if let Some(name) = name.strip_prefix("incremental-snapshot-") {
+        let mut parts = name.split('-');
+        let base_slot = parts.next()?.parse().ok()?;
+        let slot = parts.next()?.parse().ok()?;
+        return Some(ArchiveName::Incremental { base_slot, slot });
+    }
+    let slot = name
+        .strip_prefix("snapshot-")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()?;
+    Some(ArchiveName::Full { slot })
+}
+
+/// The newest full snapshot archive in the directory and the newest incremental
+/// one based on it, if any, along with the slot they snapshot
+pub fn newest_archives(directory: &Path) -> anyhow::Result<(u64, Vec<PathBuf>)> {
+    let mut archives = vec![];
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str());
+        if let Some(archive) = name.and_then(parse_archive_name) {
+            archives.push((archive, path));
+        }
+    }
+
+    let (full_slot, full_path) = archives
+        .iter()
+        .filter_map(|(archive, path)| match archive {
+            ArchiveName::Full { slot } => Some((*slot, path.clone())),
+            _ => None,
+        })
+        .max()
+        .ok_or_else(|| anyhow::anyhow!("no snapshot archive in {:?}", directory))?;
+    let incremental = archives
+        .into_iter()
+        .filter_map(|(archive, path)| match archive {
+            ArchiveName::Incremental { base_slot, slot } if base_slot == full_slot => {
+                Some((slot, path))
+            }
+            _ => None,
+        })
+        .max();
+
+    Ok(match incremental {
+        Some((slot, incremental_path)) => (slot, vec![full_path, incremental_path]),
+        None => (full_slot, vec![full_path]),
+    })
+}
+
+/// Accounts to take from a snapshot archive
+pub struct ArchiveSelection {
+    pub programs: Vec<(Pubkey, Vec<RpcFilterType>)>,
+    pub pubkeys: HashSet<Pubkey>,
+}
+
+impl ArchiveSelection {
+    fn is_selected(&self, pubkey: &Pubkey, owner: &Pubkey, data: &[u8]) -> bool {
+        self.pubkeys.contains(pubkey)
+            || self.programs.iter().any(|(program_id, filters)| {
+                program_id == owner && filters.iter().all(|filter| filter_matches(filter, data))
+            })
+    }
+
+    /// Whether some version of the account may be selected
+    fn may_select(&self, pubkey: &Pubkey, owner: &Pubkey) -> bool {
+        self.pubkeys.contains(pubkey)
+            || self
+                .programs
+                .iter()
+                .any(|(program_id, _)| program_id == owner)
+    }
+}
+
+fn filter_matches(filter: &RpcFilterType, data: &[u8]) -> bool {
+    match filter {
+        RpcFilterType::DataSize(size) => data.len() as u64 == *size,
+        RpcFilterType::Memcmp(memcmp) => memcmp.bytes_match(data),
+        RpcFilterType::TokenAccountState => {
+            // Same check as the tokenAccountState filter of the plugin
+            let is_account = data.len() == SPL_TOKEN_ACCOUNT_LEN
+                || (data.len() > SPL_TOKEN_ACCOUNT_LEN
+                    && data.len() != SPL_TOKEN_MULTISIG_LEN
+                    && data[SPL_TOKEN_ACCOUNT_LEN] == SPL_TOKEN_ACCOUNT_TYPE_ACCOUNT);
+            is_account && data[SPL_TOKEN_ACCOUNT_STATE_OFFSET] != 0
+        }
+    }
+}
+
+struct StoredAccount<'a> {
+    write_version: u64,
+    pubkey: Pubkey,
+    lamports: u64,
+    rent_epoch: u64,
+    owner: Pubkey,
+    executable: bool,
+    data: &'a [u8],
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Calls `f` for each account stored in the append-vec
+///
+/// Append-vecs in archives may have a zeroed or garbage tail, reading stops
+/// at the first account that doesn't look valid.
+fn for_each_stored_account(append_vec: &[u8], mut f: impl FnMut(StoredAccount)) {
+    let mut offset = 0;
+    while offset + HEADER_SIZE <= append_vec.len() {
+        let write_version = read_u64(append_vec, offset);
+        let data_len = read_u64(append_vec, offset + 8);
+        let pubkey = Pubkey::new(&append_vec[offset + 16..offset + 48]);
+        let lamports = read_u64(append_vec, offset + 48);
+        let rent_epoch = read_u64(append_vec, offset + 56);
+        let owner = Pubkey::new(&append_vec[offset + 64..offset + 96]);
+        let executable = append_vec[offset + 96];
+
+        let is_zeroed = write_version == 0 && data_len == 0 && pubkey == Pubkey::default();
+        if is_zeroed || data_len > MAX_PERMITTED_DATA_LENGTH || executable > 1 {
+            break;
+        }
+        let data_start = offset + HEADER_SIZE;
+        let data_end = data_start + data_len as usize;
+        if data_end > append_vec.len() {
+            break;
+        }
+
+        f(StoredAccount {
+            write_version,
+            pubkey,
+            lamports,
+            rent_epoch,
+            owner,
+            executable: executable == 1,
+            data: &append_vec[data_start..data_end],
+        });
+        offset = (data_end + 7) & !7;
+    }
+}
+
+/// Newest version of the selected accounts, by (slot, write_version)
+///
+/// Versions of an account are read in any order, and a newer version that
+/// isn't selected, like after the account was assigned to another program,
+/// hides the older ones. So archives are read twice: the first pass finds
+/// the accounts that may be selected, the second one finds their newest
+/// versions.
+#[derive(Default)]
+struct LatestAccounts {
+    /// Accounts with a version that may be selected
+    candidates: HashSet<Pubkey>,
+    /// None if the newest version is closed or not selected
+    accounts: HashMap<Pubkey, ((u64, u64), Option<Account>)>,
+}
+
+impl LatestAccounts {
+    /// First pass over the archives
+    fn add_candidate(&mut self, account: &StoredAccount, selection: &ArchiveSelection) {
+        if selection.may_select(&account.pubkey, &account.owner) {
+            self.candidates.insert(account.pubkey);
+        }
+    }
+
+    /// Second pass over the archives
+    fn insert(&mut self, slot: u64, account: StoredAccount, selection: &ArchiveSelection) {
+        if !self.candidates.contains(&account.pubkey) {
+            return;
+        }
+        let version = (slot, account.write_version);
+        if let Some((previous, _)) = self.accounts.get(&account.pubkey) {
+            if *previous >= version {
+                return;
+            }
+        }
+        let is_selected = account.lamports != 0
+            && selection.is_selected(&account.pubkey, &account.owner, account.data);
+        let pubkey = account.pubkey;
+        let account = is_selected.then(|| Account {
+            lamports: account.lamports,
+            data: account.data.to_vec(),
+            owner: account.owner,
+            executable: account.executable,
+            rent_epoch: account.rent_epoch,
+        });
+        self.accounts.insert(pubkey, (version, account));
+    }
+
+    fn into_accounts(self) -> impl Iterator<Item = (Pubkey, Account)> {
+        self.accounts
+            .into_iter()
+            .filter_map(|(pubkey, (_, account))| Some((pubkey, account?)))
+    }
+}
+
+/// Streams the append-vecs of a `.tar.zst` snapshot archive, calling `f`
+/// with the slot of each stored account
+fn read_archive(path: &Path, mut f: impl FnMut(u64, StoredAccount)) -> anyhow::Result<()> {
+    info!("reading snapshot archive {:?}", path);
+    let decoder = zstd::stream::read::Decoder::new(File::open(path)?)?;
+    let mut archive = tar::Archive::new(decoder);
+    let mut append_vec = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // Append-vecs are stored as accounts/<slot>.<id>
+        let entry_path = entry.path()?.into_owned();
+        if !entry_path.starts_with("accounts") {
+            continue;
+        }
+        let slot = match entry_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('.').next())
+            .and_then(|slot| slot.parse::<u64>().ok())
+        {
+            Some(slot) => slot,
+            None => continue,
+        };
+        append_vec.clear();
+        entry.read_to_end(&mut append_vec)?;
+        for_each_stored_account(&append_vec, |account| f(slot, account));
+    }
+    Ok(())
+}
+
+/// Loads the selected accounts from the archives found by `newest_archives`
+///
+/// Returns the accounts as writes with write_version 0 at the slot of the
+/// archives. Blocks while reading the archives, twice as explained
+/// on `LatestAccounts`.
+pub fn load_snapshot(
+    slot: u64,
+    paths: &[PathBuf],
+    selection: &ArchiveSelection,
+) -> anyhow::Result<Vec<AccountWrite>> {
+    let mut accounts = LatestAccounts::default();
+    for path in paths {
+        read_archive(path, |_, account| {
+            accounts.add_candidate(&account, selection)
+        })?;
+    }
+    for path in paths {
+        read_archive(path, |slot, account| {
+            accounts.insert(slot, account, selection)
+        })?;
+    }
+    let accounts = accounts
+        .into_accounts()
+        .map(|(pubkey, account)| AccountWrite::from(pubkey, slot, 0, account))
+        .collect::<Vec<_>>();
+    info!(
+        "snapshot archive has {} selected accounts at slot {}",
+        accounts.len(),
+        slot
+    );
+    Ok(accounts)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Serializes an account the way append-vecs store it
+    fn stored_account(
+        write_version: u64,
+        pubkey: &Pubkey,
+        lamports: u64,
+        owner: &Pubkey,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&write_version.to_le_bytes());
+        bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(pubkey.as_ref());
+        bytes.extend_from_slice(&lamports.to_le_bytes());
+        bytes.extend_from_slice(&7u64.to_le_bytes());
+        bytes.extend_from_slice(owner.as_ref());
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&[0; HASH_SIZE]);
+        bytes.extend_from_slice(data);
+        bytes.resize((bytes.len() + 7) & !7, 0);
+        bytes
+    }
+
+    fn stored_accounts(append_vec: &[u8]) -> Vec<(u64, Pubkey, u64, Vec<u8>)> {
+        let mut accounts = vec![];
+        for_each_stored_account(append_vec, |account| {
+            assert_eq!(account.rent_epoch, 7);
+            accounts.push((
+                account.write_version,
+                account.pubkey,
+                account.lamports,
+                account.data.to_vec(),
+            ))
+        });
+        accounts
+    }
+
+    #[test]
+    fn test_parse_archive_name() {
+        assert_eq!(
+            parse_archive_name("snapshot-150-AbCd.tar.zst"),
+            Some(ArchiveName::Full { slot: 150 })
+        );
+        assert_eq!(
+            parse_archive_name("incremental-snapshot-150-170-AbCd.tar.zst"),
+            Some(ArchiveName::Incremental {
+                base_slot: 150,
+                slot: 170
+            })
+        );
+        assert_eq!(parse_archive_name("snapshot-150-AbCd.tar.bz2"), None);
+        assert_eq!(parse_archive_name("snapshot-x-AbCd.tar.zst"), None);
+        assert_eq!(parse_archive_name("incremental-snapshot-150.tar.zst"), None);
+        assert_eq!(parse_archive_name("other-150-AbCd.tar.zst"), None);
+    }
+
+    #[test]
+    fn test_for_each_stored_account() {
+        let owner = Pubkey::new_unique();
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut append_vec = stored_account(3, &a, 10, &owner, &[1, 2, 3]);
+        append_vec.extend(stored_account(4, &b, 20, &owner, &[]));
+        let valid_len = append_vec.len();
+        assert_eq!(
+            stored_accounts(&append_vec),
+            vec![(3, a, 10, vec![1, 2, 3]), (4, b, 20, vec![])]
+        );
+
+        // reading stops at a zeroed tail
+        append_vec.extend(vec![0; HEADER_SIZE * 2]);
+        assert_eq!(stored_accounts(&append_vec).len(), 2);
+
+        // and at an account whose data goes past the end
+        append_vec.truncate(valid_len);
+        let mut truncated = stored_account(5, &a, 30, &owner, &[1; 16]);
+        truncated.truncate(HEADER_SIZE + 8);
+        append_vec.extend(truncated);
+        assert_eq!(stored_accounts(&append_vec).len(), 2);
+
+        // and at an implausible data length
+        append_vec.truncate(valid_len);
+        let mut invalid = stored_account(5, &a, 30, &owner, &[]);
+        invalid[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        append_vec.extend(invalid);
+        assert_eq!(stored_accounts(&append_vec).len(), 2);
+    }
+
+    #[test]
+    fn test_token_account_state_filter() {
+        let filter = RpcFilterType::TokenAccountState;
+        let mut data = vec![0; SPL_TOKEN_ACCOUNT_LEN];
+        assert!(!filter_matches(&filter, &data));
+        data[SPL_TOKEN_ACCOUNT_STATE_OFFSET] = 1;
+        assert!(filter_matches(&filter, &data));
+
+        // token-2022 accounts with extensions
+        data.resize(SPL_TOKEN_ACCOUNT_LEN + 10, 0);
+        assert!(!filter_matches(&filter, &data));
+        data[SPL_TOKEN_ACCOUNT_LEN] = SPL_TOKEN_ACCOUNT_TYPE_ACCOUNT;
+        assert!(filter_matches(&filter, &data));
+
+        // multisig accounts
+        data.resize(SPL_TOKEN_MULTISIG_LEN, 0);
+        assert!(!filter_matches(&filter, &data));
+    }
+
+    #[test]
+    fn test_latest_accounts() {
+        let program_id = Pubkey::new_unique();
+        let other_program_id = Pubkey::new_unique();
+        let selection = ArchiveSelection {
+            programs: vec![(program_id, vec![RpcFilterType::DataSize(1)])],
+            pubkeys: HashSet::new(),
+        };
+        // Stored versions by slot, in the order they are read
+        let mut versions = vec![];
+        let mut store = |slot: u64, pubkey: &Pubkey, lamports: u64, owner: &Pubkey, data: &[u8]| {
+            versions.push((slot, stored_account(1, pubkey, lamports, owner, data)))
+        };
+
+        let (a, b, c, d, e) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        // newer versions win, whatever order they are read in
+        store(5, &a, 10, &program_id, &[0]);
+        store(4, &a, 20, &program_id, &[0]);
+        // a newer version that doesn't match the filters removes the account,
+        // also if the older version is read later
+        store(5, &b, 10, &program_id, &[0]);
+        store(6, &b, 20, &program_id, &[0, 0]);
+        store(7, &c, 10, &program_id, &[0, 0]);
+        store(6, &c, 20, &program_id, &[0]);
+        // as does closing it
+        store(7, &d, 0, &other_program_id, &[]);
+        store(6, &d, 20, &program_id, &[0]);
+        // or assigning it to another program
+        store(7, &e, 10, &other_program_id, &[0]);
+        store(6, &e, 20, &program_id, &[0]);
+        // accounts of other programs aren't tracked
+        store(6, &Pubkey::new_unique(), 20, &other_program_id, &[0]);
+
+        let mut accounts = LatestAccounts::default();
+        for (_, bytes) in &versions {
+            for_each_stored_account(bytes, |account| {
+                accounts.add_candidate(&account, &selection)
+            });
+        }
+        for (slot, bytes) in &versions {
+            for_each_stored_account(bytes, |account| accounts.insert(*slot, account, &selection));
+        }
+        assert_eq!(accounts.accounts.len(), 5);
+
+        let lamports = accounts
+            .into_accounts()
+            .map(|(pubkey, account)| (pubkey, account.lamports))
+            .collect::<Vec<_>>();
+        assert_eq!(lamports, vec![(a, 10)]);
+    }
+}
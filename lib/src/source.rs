@@ -0,0 +1,418 @@
+use async_trait::async_trait;
+use solana_sdk::pubkey::Pubkey;
+
+use log::*;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+use crate::{
+    file_replay_source::FileReplaySource, grpc_plugin_source::GrpcPluginSource, metrics,
+    websocket_source::WebsocketSource, AccountWrite, SlotUpdate, SnapshotSourceConfig,
+    SourceConfig, SourceKindConfig,
+};
+
+pub struct SnapshotData {
+    /// The snapshot contains all account writes up to and including this slot
+    pub slot: u64,
+    pub accounts: Vec<AccountWrite>,
+}
+
+pub enum SourceMessage {
+    AccountWrite(AccountWrite),
+    SlotUpdate(SlotUpdate),
+    /// Snapshot accounts have write_version 0 and are never deduplicated
+    Snapshot(SnapshotData),
+}
+
+/// What a source gets to deliver its updates
+pub struct SourceContext {
+    pub sender: async_channel::Sender<SourceMessage>,
+    /// Changes whenever sources should take a new snapshot
+    pub resnapshot: watch::Receiver<()>,
+    pub metrics: metrics::Metrics,
+}
+
+/// A source of account writes, slot updates and snapshots
+///
+/// Several sources can run at once, their account writes are deduplicated by
+/// (slot, pubkey, write_version). Sources must number the writes of a pubkey
+/// within a slot consistently, starting at 1.
+#[async_trait]
+pub trait Source: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Sends updates until the source is exhausted. Sources of live data
+    /// reconnect on failure and never return.
+    async fn run(&self, context: SourceContext);
+}
+
+/// Creates the configured sources
+pub fn make_sources(config: &SourceConfig) -> anyhow::Result<Vec<Arc<dyn Source>>> {
+    config.snapshot.validate()?;
+    let sources = config
+        .grpc_sources
+        .iter()
+        .cloned()
+        .map(SourceKindConfig::Grpc)
+        .chain(config.sources.iter().cloned())
+        .map(|source| -> anyhow::Result<Arc<dyn Source>> {
+            Ok(match source {
+                SourceKindConfig::Grpc(grpc_config) => Arc::new(GrpcPluginSource::new(
+                    grpc_config,
+                    config.snapshot.clone(),
+                    config.record.as_ref(),
+                )?),
+                SourceKindConfig::Websocket(websocket_config) => Arc::new(WebsocketSource::new(
+                    websocket_config,
+                    config.snapshot.clone(),
+                )?),
+                SourceKindConfig::FileReplay(replay_config) => {
+                    Arc::new(FileReplaySource::new(replay_config)?)
+                }
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(!sources.is_empty(), "no sources are configured");
+    Ok(sources)
+}
+
+/// Number of slots before the newest rooted slot to keep numbering writes for
+const WRITE_VERSION_RETENTION_SLOTS: u64 = 50;
+
+/// Number of slots to remember the latest write_version of each pubkey for,
+/// to skip duplicates
+const DEDUP_RETENTION_SLOTS: u64 = 50;
+
+/// How often the snapshot age is updated while a source is disconnected
+const AGE_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Numbers the writes of each pubkey in a slot, starting at 1
+///
+/// For sources whose writes carry no write_version usable for deduplication.
+/// Without one, later writes in a slot would look like duplicates of the
+/// first one. write_version 0 is reserved for snapshots.
+#[derive(Default)]
+pub(crate) struct WriteVersions {
+    slots: BTreeMap<u64, HashMap<Pubkey, u64>>,
+}
+
+impl WriteVersions {
+    pub(crate) fn next(&mut self, slot: u64, pubkey: Pubkey) -> u64 {
+        let write_version = self
+            .slots
+            .entry(slot)
+            .or_default()
+            .entry(pubkey)
+            .or_insert(0);
+        *write_version += 1;
+        *write_version
+    }
+
+    /// Forgets slots that can't receive writes anymore
+    pub(crate) fn on_rooted(&mut self, rooted_slot: u64) {
+        let oldest_slot = rooted_slot.saturating_sub(WRITE_VERSION_RETENTION_SLOTS);
+        self.slots = self.slots.split_off(&oldest_slot);
+    }
+}
+
+/// Skips account writes that a different source has already sent
+///
+/// To avoid unnecessarily sending requests to SQL, we track the latest write_version
+/// for each (slot, pubkey). If an already-seen write_version comes in, it can be safely
+/// discarded.
+#[derive(Default)]
+struct WriteDeduplicator {
+    // slot -> (pubkey -> write_version)
+    latest_write: HashMap<u64, HashMap<Pubkey, u64>>,
+}
+
+impl WriteDeduplicator {
+    /// Returns whether the write wasn't seen before, and remembers it
+    fn is_new(&mut self, account_write: &AccountWrite) -> bool {
+        let pubkey_writes = self.latest_write.entry(account_write.slot).or_default();
+        if let Some(&write_version) = pubkey_writes.get(&account_write.pubkey) {
+            if account_write.write_version <= write_version {
+                return false;
+            }
+        }
+        pubkey_writes.insert(account_write.pubkey, account_write.write_version);
+        let oldest_slot = account_write.slot.saturating_sub(DEDUP_RETENTION_SLOTS);
+        self.latest_write.retain(|&k, _| k >= oldest_slot);
+        true
+    }
+}
+
+/// Metrics about the snapshots of a source
+pub(crate) struct SnapshotMetrics {
+    duration_ms: metrics::MetricU64,
+    age_secs: metrics::MetricU64,
+    snapshots: metrics::MetricU64,
+    last_snapshot: Option<Instant>,
+}
+
+impl SnapshotMetrics {
+    /// Registers `<prefix>_snapshot_duration_ms` and so on
+    pub(crate) fn new(metrics_sender: &metrics::Metrics, prefix: &str) -> Self {
+        let metric =
+            |suffix: &str| metrics_sender.register_u64(format!("{}_snapshot_{}", prefix, suffix));
+        Self {
+            duration_ms: metric("duration_ms"),
+            age_secs: metric("age_secs"),
+            snapshots: metric("count"),
+            last_snapshot: None,
+        }
+    }
+
+    pub(crate) fn on_snapshot(&mut self, started: Instant) {
+        self.duration_ms.set(started.elapsed().as_millis() as u64);
+        self.snapshots.increment();
+        self.last_snapshot = Some(Instant::now());
+    }
+
+    pub(crate) fn update_age(&mut self) {
+        if let Some(last_snapshot) = self.last_snapshot {
+            self.age_secs.set(last_snapshot.elapsed().as_secs());
+        }
+    }
+
+    /// Sleeps, keeping the age up to date, like while waiting to reconnect
+    pub(crate) async fn sleep(&mut self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        loop {
+            self.update_age();
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return;
+            }
+            tokio::time::sleep(remaining.min(AGE_UPDATE_INTERVAL)).await;
+        }
+    }
+}
+
+/// Requests new snapshots on SIGUSR1 and periodically, if configured
+///
+/// The sender must be kept alive for as long as sources run.
+fn start_resnapshot_trigger(
+    config: &SnapshotSourceConfig,
+) -> (Arc<watch::Sender<()>>, watch::Receiver<()>) {
+    let (resnapshot_sender, resnapshot_receiver) = watch::channel(());
+    let resnapshot_sender = Arc::new(resnapshot_sender);
+    {
+        let resnapshot_sender = resnapshot_sender.clone();
+        tokio::spawn(async move {
+            let mut signals = match signal(SignalKind::user_defined1()) {
+                Ok(signals) => signals,
+                Err(err) => {
+                    warn!("could not listen for SIGUSR1: {:?}", err);
+                    return;
+                }
+            };
+            while signals.recv().await.is_some() {
+                info!("SIGUSR1 received, requesting new snapshots");
+                let _ = resnapshot_sender.send(());
+            }
+        });
+    }
+    if config.resnapshot_interval_secs > 0 {
+        let resnapshot_sender = resnapshot_sender.clone();
+        let interval = Duration::from_secs(config.resnapshot_interval_secs);
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                interval.tick().await;
+                let _ = resnapshot_sender.send(());
+            }
+        });
+    }
+    (resnapshot_sender, resnapshot_receiver)
+}
+
+/// Runs all configured sources and forwards their deduplicated updates
+///
+/// Returns once all sources are exhausted, which only happens for replays.
+pub async fn process_events(
+    config: &SourceConfig,
+    account_write_queue_sender: async_channel::Sender<AccountWrite>,
+    slot_queue_sender: async_channel::Sender<SlotUpdate>,
+    metrics_sender: metrics::Metrics,
+) -> anyhow::Result<()> {
+    let sources = make_sources(config)?;
+    let (_resnapshot_sender, resnapshot_receiver) = start_resnapshot_trigger(&config.snapshot);
+
+    let (msg_sender, msg_receiver) =
+        async_channel::bounded::<SourceMessage>(config.dedup_queue_size);
+    for source in sources {
+        info!("starting source {}", source.name());
+        let context = SourceContext {
+            sender: msg_sender.clone(),
+            resnapshot: resnapshot_receiver.clone(),
+            metrics: metrics_sender.clone(),
+        };
+        tokio::spawn(async move {
+            source.run(context).await;
+            info!("source {} is done", source.name());
+        });
+    }
+    // The channel closes once all sources are done
+    drop(msg_sender);
+
+    let mut deduplicator = WriteDeduplicator::default();
+
+    let mut metric_account_writes = metrics_sender.register_u64("source_account_writes".into());
+    let mut metric_duplicate_account_writes =
+        metrics_sender.register_u64("source_duplicate_account_writes".into());
+    let mut metric_account_queue = metrics_sender.register_u64("account_write_queue".into());
+    let mut metric_slot_queue = metrics_sender.register_u64("slot_update_queue".into());
+    let mut metric_slot_updates = metrics_sender.register_u64("source_slot_updates".into());
+    let mut metric_snapshots = metrics_sender.register_u64("source_snapshots".into());
+    let mut metric_snapshot_account_writes =
+        metrics_sender.register_u64("source_snapshot_account_writes".into());
+
+    while let Ok(msg) = msg_receiver.recv().await {
+        match msg {
+            SourceMessage::AccountWrite(account_write) => {
+                metric_account_writes.increment();
+                metric_account_queue.set(account_write_queue_sender.len() as u64);
+
+                if !deduplicator.is_new(&account_write) {
+                    metric_duplicate_account_writes.increment();
+                    continue;
+                }
+
+                account_write_queue_sender
+                    .send(account_write)
+                    .await
+                    .expect("send success");
+            }
+            SourceMessage::SlotUpdate(slot_update) => {
+                metric_slot_updates.increment();
+                metric_slot_queue.set(slot_queue_sender.len() as u64);
+
+                slot_queue_sender
+                    .send(slot_update)
+                    .await
+                    .expect("send success");
+            }
+            SourceMessage::Snapshot(snapshot) => {
+                metric_snapshots.increment();
+                info!("processing snapshot for slot {}...", snapshot.slot);
+                for account_write in snapshot.accounts {
+                    metric_snapshot_account_writes.increment();
+                    metric_account_queue.set(account_write_queue_sender.len() as u64);
+
+                    account_write_queue_sender
+                        .send(account_write)
+                        .await
+                        .expect("send success");
+                }
+                info!("processing snapshot done");
+            }
+        }
+    }
+    info!("all sources are done");
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn snapshot_config(resnapshot_interval_secs: u64) -> SnapshotSourceConfig {
+        SnapshotSourceConfig {
+            rpc_http_url: String::new(),
+            program_id: String::new(),
+            programs: vec![],
+            accounts: vec![],
+            from_grpc_plugin: false,
+            archive_directory: None,
+            resnapshot_interval_secs,
+        }
+    }
+
+    fn account_write(slot: u64, pubkey: Pubkey, write_version: u64) -> AccountWrite {
+        AccountWrite {
+            pubkey,
+            slot,
+            write_version,
+            lamports: 1,
+            owner: Pubkey::default(),
+            executable: false,
+            rent_epoch: 0,
+            data: vec![],
+            is_selected: true,
+            is_closed: false,
+            data_slices: vec![],
+        }
+    }
+
+    #[test]
+    fn test_write_versions() {
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut write_versions = WriteVersions::default();
+        assert_eq!(write_versions.next(10, a), 1);
+        assert_eq!(write_versions.next(10, a), 2);
+        assert_eq!(write_versions.next(10, b), 1);
+        assert_eq!(write_versions.next(11, a), 1);
+
+        // slots within the retention are still numbered on
+        write_versions.on_rooted(10 + WRITE_VERSION_RETENTION_SLOTS);
+        assert_eq!(write_versions.next(10, a), 3);
+
+        write_versions.on_rooted(11 + WRITE_VERSION_RETENTION_SLOTS);
+        assert_eq!(
+            write_versions.slots.keys().copied().collect::<Vec<_>>(),
+            vec![11]
+        );
+        assert_eq!(write_versions.next(11, a), 2);
+    }
+
+    #[test]
+    fn test_write_deduplicator() {
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut deduplicator = WriteDeduplicator::default();
+        assert!(deduplicator.is_new(&account_write(10, a, 1)));
+        assert!(deduplicator.is_new(&account_write(10, a, 2)));
+        // another source sending the same writes
+        assert!(!deduplicator.is_new(&account_write(10, a, 1)));
+        assert!(!deduplicator.is_new(&account_write(10, a, 2)));
+        // pubkeys and slots are independent
+        assert!(deduplicator.is_new(&account_write(10, b, 1)));
+        assert!(deduplicator.is_new(&account_write(11, a, 1)));
+
+        // old slots are forgotten
+        assert!(deduplicator.is_new(&account_write(10 + DEDUP_RETENTION_SLOTS + 1, b, 1)));
+        assert!(!deduplicator.latest_write.contains_key(&10));
+        assert!(deduplicator.is_new(&account_write(10, a, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_resnapshot_trigger() {
+        let wait = Duration::from_millis(2500);
+
+        let (_sender, mut receiver) = start_resnapshot_trigger(&snapshot_config(1));
+        // the first request comes after the interval, not right away
+        let started = Instant::now();
+        tokio::time::timeout(wait, receiver.changed())
+            .await
+            .expect("resnapshot requested")
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        tokio::time::timeout(wait, receiver.changed())
+            .await
+            .expect("resnapshot requested again")
+            .unwrap();
+
+        let (_sender, mut receiver) = start_resnapshot_trigger(&snapshot_config(0));
+        assert!(tokio::time::timeout(wait, receiver.changed())
+            .await
+            .is_err());
+    }
+}
@@ -1,199 +1,331 @@
+use async_trait::async_trait;
 use jsonrpc_core::futures::StreamExt;
-use jsonrpc_core_client::transports::{http, ws};
+use jsonrpc_core_client::transports::ws;
 
-use solana_account_decoder::UiAccountEncoding;
-use solana_client::{
-    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
-    //rpc_filter::RpcFilterType,
-    rpc_response::{Response, RpcKeyedAccount},
-};
-use solana_rpc::{
-    rpc::rpc_accounts::AccountsDataClient, rpc::OptionalContext, rpc_pubsub::RpcSolPubSubClient,
-};
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
+use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
+use solana_rpc::rpc_pubsub::RpcSolPubSubClient;
 use solana_sdk::{account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey};
 
+use futures::{
+    future,
+    future::{FusedFuture, FutureExt},
+    stream::BoxStream,
+};
+
 use log::*;
 use std::{
     str::FromStr,
-    sync::Arc,
     time::{Duration, Instant},
 };
+use tokio::sync::watch;
+
+use crate::{
+    grpc_plugin_source::get_snapshot,
+    metrics,
+    source::{SnapshotMetrics, Source, SourceContext, SourceMessage, WriteVersions},
+    AccountWrite, AnyhowWrap, SlotStatus, SlotUpdate, SnapshotSourceConfig, WebsocketSourceConfig,
+};
+
+/// A connection that lasted this long resets the reconnection backoff
+const STABLE_CONNECTION_DURATION: Duration = Duration::from_secs(60);
 
-use crate::{AccountWrite, AnyhowWrap, SlotStatus, SlotUpdate, SourceConfig};
+struct WebsocketMetrics {
+    account_writes: metrics::MetricU64,
+    slot_updates: metrics::MetricU64,
+    snapshot: SnapshotMetrics,
+}
+
+fn decode_account(pubkey: &Pubkey, account: &UiAccount) -> anyhow::Result<Account> {
+    account
+        .decode()
+        .ok_or_else(|| anyhow::anyhow!("could not decode account {}", pubkey))
+}
 
-enum WebsocketMessage {
-    Single(Response<RpcKeyedAccount>),
-    Snapshot(Response<Vec<RpcKeyedAccount>>),
-    Slot(Arc<solana_client::rpc_response::SlotUpdate>),
+fn slot_update_from_rpc(update: &solana_client::rpc_response::SlotUpdate) -> Option<SlotUpdate> {
+    use solana_client::rpc_response::SlotUpdate as RpcSlotUpdate;
+    match *update {
+        RpcSlotUpdate::CreatedBank { slot, parent, .. } => Some(SlotUpdate {
+            slot,
+            parent: Some(parent),
+            status: SlotStatus::Processed,
+        }),
+        RpcSlotUpdate::OptimisticConfirmation { slot, .. } => Some(SlotUpdate {
+            slot,
+            parent: None,
+            status: SlotStatus::Confirmed,
+        }),
+        RpcSlotUpdate::Root { slot, .. } => Some(SlotUpdate {
+            slot,
+            parent: None,
+            status: SlotStatus::Rooted,
+        }),
+        _ => None,
+    }
 }
 
-// TODO: the reconnecting should be part of this
+/// Subscribes to the configured programs and accounts and streams their updates
+///
+/// A snapshot is taken after subscribing, since updates may have been missed
+/// while disconnected, and again whenever one is requested.
 async fn feed_data(
-    config: &SourceConfig,
-    sender: async_channel::Sender<WebsocketMessage>,
+    config: &WebsocketSourceConfig,
+    snapshot_config: &SnapshotSourceConfig,
+    write_versions: &mut WriteVersions,
+    resnapshot: &mut watch::Receiver<()>,
+    metrics: &mut WebsocketMetrics,
+    sender: &async_channel::Sender<SourceMessage>,
 ) -> anyhow::Result<()> {
-    let program_id = Pubkey::from_str(&config.snapshot.program_id)?;
-    let snapshot_duration = Duration::from_secs(300);
+    let programs = snapshot_config.programs()?;
+    let pubkeys = snapshot_config.account_pubkeys()?;
+    let commitment = CommitmentConfig {
+        commitment: config.commitment,
+    };
 
     let connect = ws::try_connect::<RpcSolPubSubClient>(&config.rpc_ws_url).map_err_anyhow()?;
     let client = connect.await.map_err_anyhow()?;
 
-    let rpc_client =
-        http::connect_with_options::<AccountsDataClient>(&config.snapshot.rpc_http_url, true)
-            .await
-            .map_err_anyhow()?;
-
     let account_info_config = RpcAccountInfoConfig {
         encoding: Some(UiAccountEncoding::Base64),
-        commitment: Some(CommitmentConfig::processed()),
+        commitment: Some(commitment),
         data_slice: None,
         min_context_slot: None,
     };
-    let program_accounts_config = RpcProgramAccountsConfig {
-        filters: None,
-        with_context: Some(true),
-        account_config: account_info_config.clone(),
-    };
 
-    let mut update_sub = client
-        .program_subscribe(
-            program_id.to_string(),
-            Some(program_accounts_config.clone()),
-        )
-        .map_err_anyhow()?;
-    let mut slot_sub = client.slots_updates_subscribe().map_err_anyhow()?;
+    // Notifications of all subscriptions as (pubkey, slot, account)
+    let mut account_streams: Vec<BoxStream<'static, anyhow::Result<(Pubkey, u64, Account)>>> =
+        vec![];
+    for (program_id, filters) in programs {
+        let program_accounts_config = RpcProgramAccountsConfig {
+            filters: (!filters.is_empty()).then(|| filters),
+            with_context: Some(true),
+            account_config: account_info_config.clone(),
+        };
+        let stream = client
+            .program_subscribe(program_id.to_string(), Some(program_accounts_config))
+            .map_err_anyhow()?;
+        account_streams.push(
+            stream
+                .map(|response| {
+                    let response = response.map_err_anyhow()?;
+                    let pubkey = Pubkey::from_str(&response.value.pubkey)?;
+                    let account = decode_account(&pubkey, &response.value.account)?;
+                    Ok((pubkey, response.context.slot, account))
+                })
+                .boxed(),
+        );
+    }
+    for pubkey in pubkeys {
+        let stream = client
+            .account_subscribe(pubkey.to_string(), Some(account_info_config.clone()))
+            .map_err_anyhow()?;
+        account_streams.push(
+            stream
+                .map(move |response| {
+                    let response = response.map_err_anyhow()?;
+                    let account = decode_account(&pubkey, &response.value)?;
+                    Ok((pubkey, response.context.slot, account))
+                })
+                .boxed(),
+        );
+    }
+    let mut account_stream = futures::stream::select_all(account_streams);
+    let mut slot_stream = client.slots_updates_subscribe().map_err_anyhow()?;
+    info!("websocket source {} subscribed", config.name);
 
-    let mut last_snapshot = Instant::now() - snapshot_duration;
+    let mut snapshot_future = future::Fuse::terminated();
+    let mut snapshot_started = Instant::now();
+    let mut snapshot_needed = true;
 
     loop {
-        // occasionally cause a new snapshot to be produced
-        // including the first time
-        if last_snapshot + snapshot_duration <= Instant::now() {
-            let account_snapshot = rpc_client
-                .get_program_accounts(
-                    program_id.to_string(),
-                    Some(program_accounts_config.clone()),
-                )
-                .await
-                .map_err_anyhow()?;
-            if let OptionalContext::Context(account_snapshot_response) = account_snapshot {
-                sender
-                    .send(WebsocketMessage::Snapshot(account_snapshot_response))
-                    .await
-                    .expect("sending must succeed");
-            }
-            last_snapshot = Instant::now();
+        if snapshot_needed && snapshot_future.is_terminated() {
+            snapshot_needed = false;
+            snapshot_started = Instant::now();
+            info!("requesting snapshot for websocket source {}", config.name);
+            snapshot_future =
+                tokio::spawn(get_snapshot(snapshot_config.clone(), commitment)).fuse();
         }
+        metrics.snapshot.update_age();
 
         tokio::select! {
-            account = update_sub.next() => {
-                match account {
-                    Some(account) => {
-                        sender.send(WebsocketMessage::Single(account.map_err_anyhow()?)).await.expect("sending must succeed");
-                    },
-                    None => {
-                        warn!("account stream closed");
-                        return Ok(());
-                    },
+            account = account_stream.next() => {
+                let (pubkey, slot, account) = account.ok_or_else(|| anyhow::anyhow!("account stream closed"))??;
+                trace!("account update for {} at slot {}", pubkey, slot);
+                metrics.account_writes.increment();
+                let write_version = write_versions.next(slot, pubkey);
+                sender
+                    .send(SourceMessage::AccountWrite(AccountWrite::from(pubkey, slot, write_version, account)))
+                    .await
+                    .expect("send success");
+            },
+            slot_update = slot_stream.next() => {
+                let slot_update = slot_update.ok_or_else(|| anyhow::anyhow!("slot update stream closed"))?.map_err_anyhow()?;
+                if let Some(slot_update) = slot_update_from_rpc(&slot_update) {
+                    trace!("slot update for {} ({:?})", slot_update.slot, slot_update.status);
+                    metrics.slot_updates.increment();
+                    if slot_update.status == SlotStatus::Rooted {
+                        write_versions.on_rooted(slot_update.slot);
+                    }
+                    sender.send(SourceMessage::SlotUpdate(slot_update)).await.expect("send success");
                 }
             },
-            slot_update = slot_sub.next() => {
-                match slot_update {
-                    Some(slot_update) => {
-                        sender.send(WebsocketMessage::Slot(slot_update.map_err_anyhow()?)).await.expect("sending must succeed");
-                    },
-                    None => {
-                        warn!("slot update stream closed");
-                        return Ok(());
-                    },
+            snapshot = &mut snapshot_future => {
+                let snapshot = snapshot??;
+                info!("websocket source {} has a snapshot for slot {}", config.name, snapshot.slot);
+                sender.send(SourceMessage::Snapshot(snapshot)).await.expect("send success");
+                metrics.snapshot.on_snapshot(snapshot_started);
+            },
+            result = resnapshot.changed() => {
+                if result.is_ok() {
+                    info!("resnapshot requested");
+                    snapshot_needed = true;
                 }
             },
             _ = tokio::time::sleep(Duration::from_secs(60)) => {
-                warn!("websocket timeout");
-                return Ok(())
+                anyhow::bail!("websocket hasn't sent a message in too long");
             }
         }
     }
 }
 
-// TODO: rename / split / rework
-pub async fn process_events(
-    config: &SourceConfig,
-    account_write_queue_sender: async_channel::Sender<AccountWrite>,
-    slot_queue_sender: async_channel::Sender<SlotUpdate>,
-) {
-    // Subscribe to program account updates websocket
-    let (update_sender, update_receiver) = async_channel::unbounded::<WebsocketMessage>();
-    let config = config.clone();
-    tokio::spawn(async move {
-        // if the websocket disconnects, we get no data in a while etc, reconnect and try again
-        loop {
-            let out = feed_data(&config, update_sender.clone());
-            let _ = out.await;
-        }
-    });
+/// Streams program and account updates from an RPC node's websocket
+///
+/// Useful without access to a validator running the geyser plugin. Snapshots
+/// use getProgramAccounts and getMultipleAccounts on the snapshot rpc_http_url.
+pub struct WebsocketSource {
+    config: WebsocketSourceConfig,
+    snapshot_config: SnapshotSourceConfig,
+}
 
-    //
-    // The thread that pulls updates and forwards them to postgres
-    //
+impl WebsocketSource {
+    pub fn new(
+        config: WebsocketSourceConfig,
+        snapshot_config: SnapshotSourceConfig,
+    ) -> anyhow::Result<Self> {
+        let has_subscriptions = !snapshot_config.programs()?.is_empty()
+            || !snapshot_config.account_pubkeys()?.is_empty();
+        anyhow::ensure!(
+            has_subscriptions,
+            "websocket source {} needs snapshot programs or accounts to subscribe to",
+            config.name
+        );
+        // Notifications can be dropped without notice, only new snapshots
+        // repair the missed writes
+        anyhow::ensure!(
+            snapshot_config.resnapshot_interval_secs > 0,
+            "websocket source {} needs a nonzero resnapshot_interval_secs",
+            config.name
+        );
+        Ok(Self {
+            config,
+            snapshot_config,
+        })
+    }
+}
 
-    // copy websocket updates into the postgres account write queue
-    loop {
-        let update = update_receiver.recv().await.unwrap();
-        info!("got update message");
-
-        match update {
-            WebsocketMessage::Single(update) => {
-                info!("single update");
-                let account: Account = update.value.account.decode().unwrap();
-                let pubkey = Pubkey::from_str(&update.value.pubkey).unwrap();
-                account_write_queue_sender
-                    .send(AccountWrite::from(pubkey, update.context.slot, 0, account))
-                    .await
-                    .expect("send success");
-            }
-            WebsocketMessage::Snapshot(update) => {
-                info!("snapshot update");
-                for keyed_account in update.value {
-                    let account: Account = keyed_account.account.decode().unwrap();
-                    let pubkey = Pubkey::from_str(&keyed_account.pubkey).unwrap();
-                    account_write_queue_sender
-                        .send(AccountWrite::from(pubkey, update.context.slot, 0, account))
-                        .await
-                        .expect("send success");
-                }
+#[async_trait]
+impl Source for WebsocketSource {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    async fn run(&self, context: SourceContext) {
+        let SourceContext {
+            sender,
+            mut resnapshot,
+            metrics: metrics_sender,
+        } = context;
+        let name = &self.config.name;
+
+        let mut metric_retries =
+            metrics_sender.register_u64(format!("websocket_source_{}_connection_retries", name));
+        let metric_status =
+            metrics_sender.register_string(format!("websocket_source_{}_status", name));
+        let mut metrics = WebsocketMetrics {
+            account_writes: metrics_sender
+                .register_u64(format!("websocket_source_{}_account_writes", name)),
+            slot_updates: metrics_sender
+                .register_u64(format!("websocket_source_{}_slot_updates", name)),
+            snapshot: SnapshotMetrics::new(&metrics_sender, &format!("websocket_source_{}", name)),
+        };
+
+        // Kept across reconnects, so write versions stay monotonic
+        let mut write_versions = WriteVersions::default();
+
+        let min_retry_sleep = Duration::from_secs(self.config.retry_connection_sleep_secs);
+        let max_retry_sleep = Duration::from_secs(self.config.max_retry_connection_sleep_secs);
+        let mut retry_sleep = min_retry_sleep;
+
+        // Continuously reconnect on failure, backing off exponentially
+        loop {
+            metric_status.set("connected".into());
+            let connected = Instant::now();
+            let result = feed_data(
+                &self.config,
+                &self.snapshot_config,
+                &mut write_versions,
+                &mut resnapshot,
+                &mut metrics,
+                &sender,
+            )
+            .await;
+            if connected.elapsed() >= STABLE_CONNECTION_DURATION {
+                retry_sleep = min_retry_sleep;
             }
-            WebsocketMessage::Slot(update) => {
-                info!("slot update");
-                let message = match *update {
-                    solana_client::rpc_response::SlotUpdate::CreatedBank {
-                        slot, parent, ..
-                    } => Some(SlotUpdate {
-                        slot,
-                        parent: Some(parent),
-                        status: SlotStatus::Processed,
-                    }),
-                    solana_client::rpc_response::SlotUpdate::OptimisticConfirmation {
-                        slot,
-                        ..
-                    } => Some(SlotUpdate {
-                        slot,
-                        parent: None,
-                        status: SlotStatus::Confirmed,
-                    }),
-                    solana_client::rpc_response::SlotUpdate::Root { slot, .. } => {
-                        Some(SlotUpdate {
-                            slot,
-                            parent: None,
-                            status: SlotStatus::Rooted,
-                        })
-                    }
-                    _ => None,
-                };
-                if let Some(message) = message {
-                    slot_queue_sender.send(message).await.expect("send success");
-                }
+            if let Err(err) = result {
+                warn!(
+                    "error in websocket source {}, retrying in {:?}. {:?}",
+                    name, retry_sleep, err
+                );
             }
+
+            metric_status.set("disconnected".into());
+            metric_retries.increment();
+
+            metrics.snapshot.sleep(retry_sleep).await;
+            retry_sleep = (retry_sleep * 2).min(max_retry_sleep);
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use solana_client::rpc_response::SlotUpdate as RpcSlotUpdate;
+
+    #[test]
+    fn test_slot_update_from_rpc() {
+        let slot_update = |update| {
+            slot_update_from_rpc(&update).map(|update| (update.slot, update.parent, update.status))
+        };
+        assert_eq!(
+            slot_update(RpcSlotUpdate::CreatedBank {
+                slot: 11,
+                parent: 10,
+                timestamp: 0,
+            }),
+            Some((11, Some(10), SlotStatus::Processed))
+        );
+        assert_eq!(
+            slot_update(RpcSlotUpdate::OptimisticConfirmation {
+                slot: 11,
+                timestamp: 0,
+            }),
+            Some((11, None, SlotStatus::Confirmed))
+        );
+        assert_eq!(
+            slot_update(RpcSlotUpdate::Root {
+                slot: 11,
+                timestamp: 0,
+            }),
+            Some((11, None, SlotStatus::Rooted))
+        );
+        assert_eq!(
+            slot_update(RpcSlotUpdate::FirstShredReceived {
+                slot: 11,
+                timestamp: 0,
+            }),
+            None
+        );
+    }
+}
@@ -207,15 +207,15 @@ async fn main() -> anyhow::Result<()> {
     // dropping the handle would exit the server
     let _http_server_handle = start_jsonrpc_server(config.jsonrpc_server.clone(), pnl_data)?;
 
-    // start filling chain_data from the grpc plugin source
+    // start filling chain_data from the configured sources
     let (account_write_queue_sender, slot_queue_sender) = memory_target::init(chain_data).await?;
-    grpc_plugin_source::process_events(
+    source::process_events(
         &config.source,
         account_write_queue_sender,
         slot_queue_sender,
         metrics_tx,
     )
-    .await;
+    .await?;
 
     Ok(())
 }